@@ -0,0 +1,281 @@
+/* bindings for the vendored llama.h, in the form rust-bindgen 0.65 generates them, used without the bindgen feature */
+/* update them along with llama.h, e.g. from the bindings.rs that a build with the bindgen feature writes to OUT_DIR */
+
+pub const LLAMA_FILE_MAGIC_GGJT: u32 = 1734830708;
+pub const LLAMA_FILE_MAGIC_GGLA: u32 = 1734831201;
+pub const LLAMA_FILE_MAGIC_GGMF: u32 = 1734831462;
+pub const LLAMA_FILE_MAGIC_GGML: u32 = 1734831468;
+pub const LLAMA_FILE_MAGIC_GGSN: u32 = 1734833006;
+pub const LLAMA_FILE_VERSION: u32 = 3;
+pub const LLAMA_FILE_MAGIC: u32 = 1734830708;
+pub const LLAMA_FILE_MAGIC_UNVERSIONED: u32 = 1734831468;
+pub const LLAMA_SESSION_MAGIC: u32 = 1734833006;
+pub const LLAMA_SESSION_VERSION: u32 = 1;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct llama_context {
+    _unused: [u8; 0],
+}
+pub type llama_token = ::std::os::raw::c_int;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct llama_token_data {
+    pub id: llama_token,
+    pub logit: f32,
+    pub p: f32,
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct llama_token_data_array {
+    pub data: *mut llama_token_data,
+    pub size: usize,
+    pub sorted: bool,
+}
+pub type llama_progress_callback = ::std::option::Option<
+    unsafe extern "C" fn(progress: f32, ctx: *mut ::std::os::raw::c_void),
+>;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct llama_context_params {
+    pub n_ctx: ::std::os::raw::c_int,
+    pub n_gpu_layers: ::std::os::raw::c_int,
+    pub seed: ::std::os::raw::c_int,
+    pub f16_kv: bool,
+    pub logits_all: bool,
+    pub vocab_only: bool,
+    pub use_mmap: bool,
+    pub use_mlock: bool,
+    pub embedding: bool,
+    pub progress_callback: llama_progress_callback,
+    pub progress_callback_user_data: *mut ::std::os::raw::c_void,
+}
+pub const llama_ftype_LLAMA_FTYPE_ALL_F32: llama_ftype = 0;
+pub const llama_ftype_LLAMA_FTYPE_MOSTLY_F16: llama_ftype = 1;
+pub const llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_0: llama_ftype = 2;
+pub const llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_1: llama_ftype = 3;
+pub const llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_1_SOME_F16: llama_ftype = 4;
+pub const llama_ftype_LLAMA_FTYPE_MOSTLY_Q8_0: llama_ftype = 7;
+pub const llama_ftype_LLAMA_FTYPE_MOSTLY_Q5_0: llama_ftype = 8;
+pub const llama_ftype_LLAMA_FTYPE_MOSTLY_Q5_1: llama_ftype = 9;
+pub type llama_ftype = ::std::os::raw::c_uint;
+extern "C" {
+    pub fn llama_context_default_params() -> llama_context_params;
+}
+extern "C" {
+    pub fn llama_mmap_supported() -> bool;
+}
+extern "C" {
+    pub fn llama_mlock_supported() -> bool;
+}
+extern "C" {
+    pub fn llama_init_backend();
+}
+extern "C" {
+    pub fn llama_time_us() -> i64;
+}
+extern "C" {
+    pub fn llama_init_from_file(
+        path_model: *const ::std::os::raw::c_char,
+        params: llama_context_params,
+    ) -> *mut llama_context;
+}
+extern "C" {
+    pub fn llama_free(ctx: *mut llama_context);
+}
+extern "C" {
+    pub fn llama_model_quantize(
+        fname_inp: *const ::std::os::raw::c_char,
+        fname_out: *const ::std::os::raw::c_char,
+        ftype: llama_ftype,
+        nthread: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_apply_lora_from_file(
+        ctx: *mut llama_context,
+        path_lora: *const ::std::os::raw::c_char,
+        path_base_model: *const ::std::os::raw::c_char,
+        n_threads: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_get_kv_cache_token_count(ctx: *const llama_context) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_set_rng_seed(ctx: *mut llama_context, seed: ::std::os::raw::c_int);
+}
+extern "C" {
+    pub fn llama_get_state_size(ctx: *const llama_context) -> usize;
+}
+extern "C" {
+    pub fn llama_copy_state_data(ctx: *mut llama_context, dst: *mut u8) -> usize;
+}
+extern "C" {
+    pub fn llama_set_state_data(ctx: *mut llama_context, src: *mut u8) -> usize;
+}
+extern "C" {
+    pub fn llama_load_session_file(
+        ctx: *mut llama_context,
+        path_session: *const ::std::os::raw::c_char,
+        tokens_out: *mut llama_token,
+        n_token_capacity: usize,
+        n_token_count_out: *mut usize,
+    ) -> bool;
+}
+extern "C" {
+    pub fn llama_save_session_file(
+        ctx: *mut llama_context,
+        path_session: *const ::std::os::raw::c_char,
+        tokens: *const llama_token,
+        n_token_count: usize,
+    ) -> bool;
+}
+extern "C" {
+    pub fn llama_eval(
+        ctx: *mut llama_context,
+        tokens: *const llama_token,
+        n_tokens: ::std::os::raw::c_int,
+        n_past: ::std::os::raw::c_int,
+        n_threads: ::std::os::raw::c_int,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_tokenize(
+        ctx: *mut llama_context,
+        text: *const ::std::os::raw::c_char,
+        tokens: *mut llama_token,
+        n_max_tokens: ::std::os::raw::c_int,
+        add_bos: bool,
+    ) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_n_vocab(ctx: *const llama_context) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_n_ctx(ctx: *const llama_context) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_n_embd(ctx: *const llama_context) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn llama_get_logits(ctx: *mut llama_context) -> *mut f32;
+}
+extern "C" {
+    pub fn llama_get_embeddings(ctx: *mut llama_context) -> *mut f32;
+}
+extern "C" {
+    pub fn llama_token_to_str(
+        ctx: *const llama_context,
+        token: llama_token,
+    ) -> *const ::std::os::raw::c_char;
+}
+extern "C" {
+    pub fn llama_token_bos() -> llama_token;
+}
+extern "C" {
+    pub fn llama_token_eos() -> llama_token;
+}
+extern "C" {
+    pub fn llama_token_nl() -> llama_token;
+}
+extern "C" {
+    pub fn llama_sample_repetition_penalty(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        last_tokens: *const llama_token,
+        last_tokens_size: usize,
+        penalty: f32,
+    );
+}
+extern "C" {
+    pub fn llama_sample_frequency_and_presence_penalties(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        last_tokens: *const llama_token,
+        last_tokens_size: usize,
+        alpha_frequency: f32,
+        alpha_presence: f32,
+    );
+}
+extern "C" {
+    pub fn llama_sample_softmax(ctx: *mut llama_context, candidates: *mut llama_token_data_array);
+}
+extern "C" {
+    pub fn llama_sample_top_k(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        k: ::std::os::raw::c_int,
+        min_keep: usize,
+    );
+}
+extern "C" {
+    pub fn llama_sample_top_p(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        p: f32,
+        min_keep: usize,
+    );
+}
+extern "C" {
+    pub fn llama_sample_tail_free(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        z: f32,
+        min_keep: usize,
+    );
+}
+extern "C" {
+    pub fn llama_sample_typical(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        p: f32,
+        min_keep: usize,
+    );
+}
+extern "C" {
+    pub fn llama_sample_temperature(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        temp: f32,
+    );
+}
+extern "C" {
+    pub fn llama_sample_token_mirostat(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        tau: f32,
+        eta: f32,
+        m: ::std::os::raw::c_int,
+        mu: *mut f32,
+    ) -> llama_token;
+}
+extern "C" {
+    pub fn llama_sample_token_mirostat_v2(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+        tau: f32,
+        eta: f32,
+        mu: *mut f32,
+    ) -> llama_token;
+}
+extern "C" {
+    pub fn llama_sample_token_greedy(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+    ) -> llama_token;
+}
+extern "C" {
+    pub fn llama_sample_token(
+        ctx: *mut llama_context,
+        candidates: *mut llama_token_data_array,
+    ) -> llama_token;
+}
+extern "C" {
+    pub fn llama_print_timings(ctx: *mut llama_context);
+}
+extern "C" {
+    pub fn llama_reset_timings(ctx: *mut llama_context);
+}
+extern "C" {
+    pub fn llama_print_system_info() -> *const ::std::os::raw::c_char;
+}
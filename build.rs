@@ -1,16 +1,97 @@
-use std::{path::Path, env::var_os};
+use std::{path::{Path, PathBuf}, env::{var, var_os}};
+
+// set by cargo for each enabled feature, e.g. --features cublas
+fn feature(name: &str) -> bool {
+    var_os("CARGO_FEATURE_".to_owned() + name).is_some()
+}
+
+// read when the build script runs rather than when it is compiled, and tracked for rebuilds
+fn env(name: &str) -> Option<String> {
+    println!("cargo:rerun-if-env-changed={}", name);
+    var(name).ok()
+}
+
+// (1, flag) | (2, define) | (3, include dir) for compiler flags, (1, lib) | (2, lib dir) for linker flags
+type Flags = Vec<(u8, String)>;
+
+// include dirs and libraries from pkg-config, or the usual locations when pkg-config does not know the library
+fn probe(name: &str, includes: &[&str], libs: &[&str], lib_dirs: &[&str]) -> (Flags, Flags) {
+    match pkg_config::Config::new().cargo_metadata(false).probe(name) {
+        Ok(library) => (
+            library.include_paths.iter().map(|path| (3, path.to_string_lossy().into_owned())).collect(),
+            library.libs.into_iter().map(|lib| (1, lib))
+                .chain(library.link_paths.iter().map(|path| (2, path.to_string_lossy().into_owned())))
+                .collect(),
+        ),
+        Err(_) => (
+            includes.iter().map(|path| (3, path.to_string())).collect(),
+            libs.iter().map(|lib| (1, lib.to_string()))
+                .chain(lib_dirs.iter().map(|path| (2, path.to_string())))
+                .collect(),
+        ),
+    }
+}
 
 fn main() {
+    let out_dir = var_os("OUT_DIR").unwrap();
+    let lib_dir = env("LLAMA_LIB_DIR");
+    let header = if lib_dir.is_some() || feature("SYSTEM_LLAMA") {
+        for backend in ["OPENBLAS", "BLIS", "CUBLAS", "CLBLAST"] {
+            if feature(backend) {
+                println!("cargo:warning={} has no effect on an external llama library", backend.to_lowercase());
+            }
+        }
+        link_external(lib_dir).map(|include_dir| Path::new(&include_dir).join("llama.h"))
+    } else {
+        compile_vendored();
+        None
+    };
+    write_bindings(Path::new(&out_dir), header);
+}
+
+// links a llama library built elsewhere, e.g. a llama.cpp release, from LLAMA_LIB_DIR or found with pkg-config
+// returns the directory with its llama.h when known
+fn link_external(lib_dir: Option<String>) -> Option<String> {
+    // reported by the server as its backend
+    println!("cargo:rustc-env=LLAMA_EXTERNAL_LIBRARY={}", lib_dir.as_deref().unwrap_or("pkg-config"));
+    match lib_dir {
+        Some(lib_dir) => {
+            println!("cargo:rustc-link-search=native={}", lib_dir);
+            println!("cargo:rustc-link-lib=llama");
+            if var_os("CARGO_CFG_UNIX").is_some() {
+                // found at runtime without LD_LIBRARY_PATH
+                println!("cargo:rustc-link-arg=-Wl,-rpath,{}", lib_dir);
+            }
+            env("LLAMA_INCLUDE_DIR")
+        }
+        None => {
+            let library = pkg_config::Config::new().probe("llama")
+                .expect("LLAMA_LIB_DIR is not set and pkg-config could not find llama");
+            library.include_paths.first().map(|path| path.to_string_lossy().into_owned())
+        }
+    }
+}
+
+// compiles ggml.c and llama.cpp from the crate root
+fn compile_vendored() {
     //no power9, windows later, mac later
-    let mut cflags: Vec<(u8, String)> = Vec::new();
-    let mut ldflags: Vec<(u8, String)> = Vec::new();
+    // cfg! in a build script describes the machine running it, cargo passes the target in the environment
+    let target_arch = var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let target_features = var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    let target_features: Vec<&str> = target_features.split(',').collect();
+    let unix = var_os("CARGO_CFG_UNIX").is_some();
+    // tuning for the build machine only makes sense when the binary runs there,
+    // portable builds target the baseline of the architecture plus whatever is requested
+    let native = !feature("PORTABLE") && env("LLAMA_PORTABLE").is_none() && var("TARGET") == var("HOST");
+    let mut cflags: Flags = Vec::new();
+    let mut ldflags: Flags = Vec::new();
     let mut objs: Vec<&str> = Vec::new();
     cflags.push((1, "-O3".to_owned()));
     cflags.push((1, "-Wall".to_owned()));
     cflags.push((1, "-Wextra".to_owned()));
     cflags.push((1, "-Wpedantic".to_owned()));
     cflags.push((1, "-Wcast-qual".to_owned()));
-    let mut cxxflags: Vec<(u8, String)> = cflags.clone();
+    let mut cxxflags: Flags = cflags.clone();
     cflags.push((1, "-std=c11".to_owned()));
     cxxflags.push((1, "-std=c++11".to_owned()));
     cxxflags.push((1, "-Wno-unused-function".to_owned()));
@@ -19,37 +100,44 @@ fn main() {
     cflags.push((1, "-Wshadow".to_owned()));
     cflags.push((1, "-Wstrict-prototypes".to_owned()));
     cflags.push((1, "-Wpointer-arith".to_owned()));
-    if cfg!(unix) {
+    if unix {
         cflags.push((1, "-pthread".to_owned()));
         cxxflags.push((1, "-pthread".to_owned()));
     }
-    if cfg!(any(target_arch = "x86_64", target_arch = "x86")) {
-        cflags.push((1, "-march=native".to_owned()));
-        cflags.push((1, "-mtune=native".to_owned()));
-        cflags.push((1, "-mfma".to_owned()));
-        cflags.push((1, "-mf16c".to_owned()));
-        cflags.push((1, "-mavx".to_owned()));
-        cxxflags.push((1, "-march=native".to_owned()));
-        cxxflags.push((1, "-mtune=native".to_owned()));
-        cxxflags.push((1, "-mfma".to_owned()));
-        cxxflags.push((1, "-mf16c".to_owned()));
-        cxxflags.push((1, "-mavx".to_owned()));
-    }
-    if option_env!("LLAMA_OPENBLAS").is_some() && cfg!(unix) {
+    if target_arch == "x86_64" || target_arch == "x86" {
+        if native {
+            cflags.push((1, "-march=native".to_owned()));
+            cflags.push((1, "-mtune=native".to_owned()));
+            cxxflags.push((1, "-march=native".to_owned()));
+            cxxflags.push((1, "-mtune=native".to_owned()));
+        }
+        // requested with the cargo feature of the same name, or RUSTFLAGS="-C target-feature=+avx2" or -C target-cpu
+        for isa in ["avx", "avx2", "fma", "f16c"] {
+            if feature(&isa.to_uppercase()) || target_features.contains(&isa) {
+                cflags.push((1, format!("-m{}", isa)));
+                cxxflags.push((1, format!("-m{}", isa)));
+            }
+        }
+    }
+    if feature("OPENBLAS") && unix {
         cflags.push((2, "GGML_USE_OPENBLAS".to_owned()));
-        cflags.push((3, "/usr/local/include/openblas".to_owned()));
-        cflags.push((3, "/usr/include/openblas".to_owned()));
-        ldflags.push((1, "openblas".to_owned()));
-        ldflags.push((1, "cblas".to_owned())); //possible error on arch
+        let (includes, libs) = probe("openblas",
+            &["/usr/local/include/openblas", "/usr/include/openblas"],
+            &["openblas", "cblas"], //possible error on arch
+            &[]);
+        cflags.extend(includes);
+        ldflags.extend(libs);
     }
-    if option_env!("LLAMA_BLIS").is_some() && cfg!(unix) {
+    if feature("BLIS") && unix {
         cflags.push((2, "GGML_USE_OPENBLAS".to_owned()));
-        cflags.push((3, "/usr/local/include/blis".to_owned()));
-        cflags.push((3, "/usr/include/blis".to_owned()));
-        ldflags.push((2, "blis".to_owned()));
-        ldflags.push((2, "/usr/local/lib".to_owned()))
+        let (includes, libs) = probe("blis",
+            &["/usr/local/include/blis", "/usr/include/blis"],
+            &["blis"],
+            &["/usr/local/lib"]);
+        cflags.extend(includes);
+        ldflags.extend(libs);
     }
-    if option_env!("LLAMA_CUBLAS").is_some() && cfg!(unix) {
+    if feature("CUBLAS") && unix {
         cflags.push((2, "GGML_USE_CUBLAS".to_owned()));
         cflags.push((3, "/usr/local/cuda/include".to_owned()));
         cflags.push((3, "/opt/cuda/include".to_owned()));
@@ -65,32 +153,29 @@ fn main() {
         ldflags.push((1, "rt".to_owned()));
         ldflags.push((2, "/usr/local/cuda/lib64".to_owned()));
         ldflags.push((2, "/opt/cuda/lib64".to_owned()));
-        if let Some(cuda_path) = option_env!("CUDA_PATH") {
-            cflags.push((3, (cuda_path.to_owned() + "/targets/x86_64-linux/include")));
-            cxxflags.push((3, (cuda_path.to_owned() + "/targets/x86_64-linux/include")));
-            ldflags.push((2, (cuda_path.to_owned() + "/targets/x86_64-linux/lib")));
+        if let Some(cuda_path) = env("CUDA_PATH") {
+            // arm servers have their own cuda target
+            let cuda_target = format!("{}/targets/{}-linux", cuda_path, if target_arch == "aarch64" { "sbsa" } else { &target_arch });
+            cflags.push((3, cuda_target.clone() + "/include"));
+            cxxflags.push((3, cuda_target.clone() + "/include"));
+            ldflags.push((2, cuda_target + "/lib"));
         }
         objs.push("ggml-cuda.o");
-        let ggml_cuda_dmmv_x = if let Some(val) = option_env!("LLAMA_CUDA_DMMV_X") {
-            val
-        } else {
-            "32"
-        };
-        let ggml_cuda_dmmv_y = if let Some(val) = option_env!("LLAMA_CUDA_DMMV_Y") {
-            val
-        } else {
-            "1"
-        };
+        let ggml_cuda_dmmv_x = env("LLAMA_CUDA_DMMV_X").unwrap_or_else(|| "32".to_owned());
+        let ggml_cuda_dmmv_y = env("LLAMA_CUDA_DMMV_Y").unwrap_or_else(|| "1".to_owned());
         let build = &mut cc::Build::new();
         let cuda = build
             .cuda(true)
             .flag("--std=c++14")
             .flag("--forward-unknown-to-host-compiler")
-            .flag("-arch=native")
             .file("ggml-cuda.cu")
             .include(".")
-            .define("GGML_CUDA_DMMV_X", ggml_cuda_dmmv_x)
-            .define("GGML_CUDA_DMMV_Y", ggml_cuda_dmmv_y);
+            .define("GGML_CUDA_DMMV_X", ggml_cuda_dmmv_x.as_str())
+            .define("GGML_CUDA_DMMV_Y", ggml_cuda_dmmv_y.as_str());
+        // like the cpu flags, only the build machine's gpu when the binary runs there, otherwise nvcc's default architectures
+        if native {
+            cuda.flag("-arch=native");
+        }
         for arg in cxxflags.as_slice() {
             if arg.0 == 1 {
                 cuda.flag_if_supported(&arg.1);
@@ -103,11 +188,15 @@ fn main() {
         cuda.flag("-Wno-pedantic").compile("ggml-cuda");
         println!("cargo:rerun-if-changed=ggml-cuda.cu");
     }
-    if option_env!("LLAMA_CLBLAST").is_some() && cfg!(unix) {
+    if feature("CLBLAST") && unix {
         cflags.push((2, "GGML_USE_CLBLAST".to_owned()));
         cxxflags.push((2, "GGML_USE_CLBLAST".to_owned()));
-        ldflags.push((1, "clblast".to_owned()));
-        ldflags.push((1, "OpenCL".to_owned()));
+        for (name, lib) in [("clblast", "clblast"), ("OpenCL", "OpenCL")] {
+            let (includes, libs) = probe(name, &[], &[lib], &[]);
+            cflags.extend(includes.clone());
+            cxxflags.extend(includes);
+            ldflags.extend(libs);
+        }
         objs.push("ggml-opencl.o");
         let build = &mut cc::Build::new();
         let opencl = build.cpp(true).file("ggml-opencl.cpp").include(".");
@@ -123,11 +212,11 @@ fn main() {
         opencl.compile("ggml-opencl");
         println!("cargo:rerun-if-changed=ggml-opencl.cpp");
     }
-    if cfg!(target_arch="aarch64") {
+    if target_arch == "aarch64" && native {
         cflags.push((1, "-mcpu=native".to_owned()));
         cxxflags.push((1, "-mcpu=native".to_owned()));
     }
-    if cfg!(target_arch="arm") { // combine all arm params, hope they work
+    if target_arch == "arm" { // combine all arm params, hope they work
         cflags.push((1, "-mfpu=neon-fp-armv8".to_owned()));
         cflags.push((1, "-mfp16-format=ieee".to_owned()));
         cflags.push((1, "-mno-unaligned-access".to_owned()));
@@ -203,13 +292,29 @@ fn main() {
     }
     println!("cargo:rerun-if-changed=llama.cpp");
     println!("cargo:rerun-if-changed=ggml.c");
+}
+
+// from the external library's header when it was found, otherwise from the vendored llama.h
+#[cfg(feature = "bindgen")]
+fn write_bindings(out_dir: &Path, header: Option<PathBuf>) {
+    let header = header.unwrap_or_else(|| PathBuf::from("wrapper.h"));
     println!("cargo:rerun-if-changed=wrapper.h");
     let bindings = bindgen::Builder::default()
-        .header("wrapper.h")
+        .header(header.to_string_lossy())
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
         .generate()
         .expect("Unable to generate bindings");
-    bindings.write_to_file(Path::new(out_dir).join("bindings.rs"))
+    bindings.write_to_file(out_dir.join("bindings.rs"))
         .expect("Unable to write bindings");
-    //todo: add configuration options for gpu offload enable
+}
+
+// bindings/bindings.rs is checked in and matches the vendored llama.h, so libclang is not needed
+#[cfg(not(feature = "bindgen"))]
+fn write_bindings(out_dir: &Path, header: Option<PathBuf>) {
+    if header.is_some() {
+        println!("cargo:warning=using the checked-in bindings, the external library must match the vendored llama.h");
+    }
+    println!("cargo:rerun-if-changed=bindings/bindings.rs");
+    std::fs::copy("bindings/bindings.rs", out_dir.join("bindings.rs"))
+        .expect("Unable to copy bindings");
 }
@@ -0,0 +1,149 @@
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    serde::json::Json,
+    Catcher,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fs::read_to_string, path::Path};
+
+use crate::api::ModelEventResponse;
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    INFERENCE,
+    ADMIN,
+}
+
+#[derive(Deserialize)]
+struct KeyEntry {
+    key: String,
+    #[serde(flatten)]
+    settings: KeySettings,
+}
+
+#[derive(Clone, Deserialize)]
+struct KeySettings {
+    scopes: Vec<Scope>,
+    // overrides for the server-wide rate limits
+    requests_per_minute: Option<u32>,
+    token_quota: Option<u64>,
+}
+
+// configured keys, authentication is disabled when empty
+pub struct ApiKeys {
+    keys: HashMap<String, KeySettings>,
+}
+
+impl ApiKeys {
+    // keys passed on the command line get every scope, keys file entries get their own
+    pub fn load(cli_keys: &[String], keys_file: Option<&Path>) -> Result<ApiKeys, String> {
+        let mut keys: HashMap<String, KeySettings> = HashMap::new();
+        for key in cli_keys {
+            keys.insert(
+                key.clone(),
+                KeySettings {
+                    scopes: vec![Scope::INFERENCE, Scope::ADMIN],
+                    requests_per_minute: None,
+                    token_quota: None,
+                },
+            );
+        }
+        if let Some(path) = keys_file {
+            let contents = read_to_string(path)
+                .map_err(|e| format!("Unable to read keys file {}: {}", path.display(), e))?;
+            let entries: Vec<KeyEntry> = rocket::serde::json::from_str(&contents)
+                .map_err(|e| format!("Invalid keys file {}: {}", path.display(), e))?;
+            for entry in entries {
+                keys.insert(entry.key, entry.settings);
+            }
+        }
+        Ok(ApiKeys { keys })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// request guard for a valid `Authorization: Bearer <key>` header
+#[derive(Clone)]
+pub struct ApiKey {
+    pub key: Option<String>,
+    pub requests_per_minute: Option<u32>,
+    pub token_quota: Option<u64>,
+    scopes: Vec<Scope>,
+}
+
+impl ApiKey {
+    // the key of every request while authentication is disabled
+    pub fn anonymous() -> ApiKey {
+        ApiKey {
+            key: None,
+            requests_per_minute: None,
+            token_quota: None,
+            scopes: vec![Scope::INFERENCE, Scope::ADMIN],
+        }
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    // what files store in place of the key, a hex SHA-256 digest
+    pub fn id(&self) -> Option<String> {
+        self.key.as_ref().map(|key| {
+            Sha256::digest(key.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect()
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let api_keys = match request.rocket().state::<ApiKeys>() {
+            Some(api_keys) if !api_keys.is_empty() => api_keys,
+            _ => return Outcome::Success(ApiKey::anonymous()),
+        };
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, key)| key.trim());
+        match bearer.and_then(|key| api_keys.keys.get_key_value(key)) {
+            Some((key, settings)) => Outcome::Success(ApiKey {
+                key: Some(key.clone()),
+                requests_per_minute: settings.requests_per_minute,
+                token_quota: settings.token_quota,
+                scopes: settings.scopes.clone(),
+            }),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[rocket::catch(401)]
+fn unauthorized() -> Json<ModelEventResponse> {
+    Json(ModelEventResponse::ERROR {
+        message: Some("Missing or invalid API key".to_owned()),
+    })
+}
+
+#[rocket::catch(403)]
+fn forbidden() -> Json<ModelEventResponse> {
+    Json(ModelEventResponse::ERROR {
+        message: Some("API key is not permitted to perform this operation".to_owned()),
+    })
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    rocket::catchers![unauthorized, forbidden]
+}
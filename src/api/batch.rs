@@ -0,0 +1,91 @@
+use rocket::{http::Status, serde::json::Value};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+use crate::api::auth::{ApiKey, Scope};
+
+// finished batches are dropped after this long
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchStatus {
+    RUNNING,
+    DONE,
+}
+
+#[derive(Clone, Serialize)]
+pub struct BatchProgress {
+    pub status: BatchStatus,
+    pub total: usize,
+    pub completed: usize,
+    // one completion or error per prompt, in the order they were submitted
+    pub message: Vec<Value>,
+}
+
+struct Batch {
+    owner: Option<String>,
+    progress: BatchProgress,
+    finished: Option<Instant>,
+}
+
+// results of batches submitted in async mode, kept in memory until retrieved or expired
+#[derive(Clone, Default)]
+pub struct Batches {
+    batches: Arc<Mutex<HashMap<String, Batch>>>,
+}
+
+impl Batches {
+    pub fn create(&self, owner: Option<String>, total: usize) -> String {
+        let id = Uuid::new_v4().to_string();
+        let mut batches = self.batches.lock().unwrap();
+        batches.retain(|_, batch| {
+            batch
+                .finished
+                .is_none_or(|finished| finished.elapsed() < RETENTION)
+        });
+        batches.insert(
+            id.clone(),
+            Batch {
+                owner,
+                progress: BatchProgress {
+                    status: BatchStatus::RUNNING,
+                    total,
+                    completed: 0,
+                    message: Vec::with_capacity(total),
+                },
+                finished: None,
+            },
+        );
+        id
+    }
+
+    pub fn push(&self, id: &str, result: Value) {
+        if let Some(batch) = self.batches.lock().unwrap().get_mut(id) {
+            batch.progress.message.push(result);
+            batch.progress.completed += 1;
+        }
+    }
+
+    pub fn finish(&self, id: &str) {
+        if let Some(batch) = self.batches.lock().unwrap().get_mut(id) {
+            batch.progress.status = BatchStatus::DONE;
+            batch.finished = Some(Instant::now());
+        }
+    }
+
+    // only the key that submitted the batch or an admin may read it
+    pub fn get(&self, id: &str, api_key: &ApiKey) -> Result<BatchProgress, Status> {
+        let batches = self.batches.lock().unwrap();
+        let batch = batches.get(id).ok_or(Status::NotFound)?;
+        if batch.owner != api_key.key && !api_key.allows(Scope::ADMIN) {
+            return Err(Status::Forbidden);
+        }
+        Ok(batch.progress.clone())
+    }
+}
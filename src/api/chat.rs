@@ -0,0 +1,237 @@
+use log::Level;
+use rocket::{
+    futures::{SinkExt, StreamExt},
+    serde::json::{from_str, json, Value},
+    tokio::{
+        select,
+        sync::{mpsc, RwLock},
+        task::{spawn, JoinHandle},
+    },
+};
+use rocket_ws::{result::Error, stream::DuplexStream, Message};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Instant};
+
+use crate::{
+    api::{
+        finish_completion,
+        jobs::{JobHandle, Jobs},
+        logging::{self, event},
+        metrics::Metrics,
+        ratelimit::{Client, RateLimiter},
+        start_completion, start_job, CompletionResponse, MainState, ModelEventResponse, Worker,
+    },
+    engine::{
+        inference::{Completion, GenerationParams, Interrupt, Limits},
+        models::SessionParams,
+        tokenize_text, LlamaContext,
+    },
+    sys::llama_token,
+};
+
+#[derive(Deserialize)]
+#[serde(tag = "event")]
+#[serde(rename_all = "lowercase")]
+enum ChatEvent {
+    // appended to the conversation as is, formatting turns is up to the client
+    MESSAGE {
+        message: String,
+        #[serde(flatten)]
+        params: Box<GenerationParams>,
+    },
+    // stops the turn being generated, what was generated so far stays in the conversation
+    INTERRUPT,
+    // starts a new conversation
+    RESET,
+}
+
+// a turn being generated, dropping it cancels the generation
+struct Turn {
+    recv: mpsc::UnboundedReceiver<String>,
+    finished: JoinHandle<Result<Completion, String>>,
+    job: JobHandle,
+    start: Instant,
+    first_token: bool,
+    // the model the turn was generated with, its tokens mean nothing to another one
+    session_params: Option<SessionParams>,
+}
+
+// the conversation's tokens and the model they were evaluated with
+#[derive(Default)]
+struct History {
+    tokens: Vec<llama_token>,
+    session_params: Option<SessionParams>,
+}
+
+async fn next_piece(turn: &mut Option<Turn>) -> Option<String> {
+    turn.as_mut()?.recv.recv().await
+}
+
+async fn send(stream: &mut DuplexStream, response: &impl Serialize) -> Result<(), Error> {
+    stream
+        .send(Message::Text(json!(response).to_string()))
+        .await
+}
+
+fn error(message: &str) -> ModelEventResponse {
+    ModelEventResponse::ERROR {
+        message: Some(message.to_owned()),
+    }
+}
+
+// an interactive conversation over a WebSocket, like llama.cpp's interactive mode
+// the conversation is kept as tokens so the KV cache still matches it on the next turn,
+// unless another request used the context in between
+// the worker is taken for every turn, so an open session neither keeps the model loaded nor
+// keeps using it after another one was loaded
+pub struct ChatSession {
+    pub state: Arc<RwLock<MainState>>,
+    pub metrics: Metrics,
+    pub rate_limiter: RateLimiter,
+    pub client: Client,
+    pub limits: Limits,
+    pub jobs: Jobs,
+    pub log_fields: Value,
+    pub request_id: String,
+}
+
+impl ChatSession {
+    async fn start_turn(
+        &self,
+        history: &History,
+        message: String,
+        mut params: GenerationParams,
+        turn: usize,
+    ) -> Result<Turn, String> {
+        // the connection was only checked once, every turn is a request of its own
+        self.rate_limiter
+            .check(&self.client)
+            .map_err(|retry_after| {
+                format!("Rate limit exceeded, retry after {} seconds", retry_after)
+            })?;
+        let (worker, model) = {
+            let state = self.state.read().await;
+            (Worker::of(&state)?, state.current_model.clone())
+        };
+        let history = if history.session_params == worker.session_params {
+            history.tokens.clone()
+        } else {
+            Vec::new()
+        };
+        let job = start_job(&self.jobs, &self.request_id, &self.client).map_err(|(_, e)| e)?;
+        params.clamp(&self.limits)?;
+        let start = Instant::now();
+        let mut log_fields = self.log_fields.clone();
+        log_fields["model"] = json!(model);
+        log_fields["turn"] = json!(turn);
+        if logging::log_prompts() {
+            log_fields["prompt"] = json!(message);
+        }
+        let interrupt = Interrupt::new(job.cancelled(), start, &params)?;
+        let prompt = move |ctx: &LlamaContext| {
+            let bos = history.is_empty();
+            let mut tokens = history;
+            tokens.extend(tokenize_text(ctx, &message, bos)?);
+            Ok(tokens)
+        };
+        let (sender, recv) = mpsc::unbounded_channel::<String>();
+        let session_params = worker.session_params.clone();
+        let metrics = self.metrics.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let client = self.client.clone();
+        // runs on its own task so a disconnected client is still charged
+        let finished = spawn(async move {
+            let (mut pieces, handle) =
+                start_completion(&worker, &metrics, prompt, params, interrupt, None).await?;
+            while let Some(piece) = pieces.recv().await {
+                sender.send(piece).ok();
+            }
+            finish_completion(&metrics, &rate_limiter, &client, start, log_fields, handle).await
+        });
+        Ok(Turn {
+            recv,
+            finished,
+            job,
+            start,
+            first_token: true,
+            session_params,
+        })
+    }
+
+    pub async fn run(self, mut stream: DuplexStream) -> Result<(), Error> {
+        let mut history = History::default();
+        let mut turn: Option<Turn> = None;
+        let mut turns = 0;
+        loop {
+            select! {
+                message = stream.next() => {
+                    let text = match message {
+                        Some(Ok(Message::Text(text))) => text,
+                        Some(Ok(Message::Close(_))) | None => break,
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(e),
+                    };
+                    match from_str::<ChatEvent>(&text) {
+                        Ok(ChatEvent::MESSAGE { .. }) if turn.is_some() => {
+                            send(&mut stream, &error("A turn is already being generated")).await?;
+                        }
+                        Ok(ChatEvent::MESSAGE { message, params }) => {
+                            match self.start_turn(&history, message, *params, turns).await {
+                                Ok(started) => {
+                                    turn = Some(started);
+                                    turns += 1;
+                                }
+                                Err(e) => send(&mut stream, &error(&e)).await?,
+                            }
+                        }
+                        Ok(ChatEvent::INTERRUPT) => match &turn {
+                            // the turn finishes with a cancelled finish_reason
+                            Some(turn) => turn.job.cancel(),
+                            None => send(&mut stream, &error("No turn is being generated")).await?,
+                        },
+                        Ok(ChatEvent::RESET) => {
+                            turn = None;
+                            history = History::default();
+                            send(&mut stream, &ModelEventResponse::OK { message: None }).await?;
+                        }
+                        Err(e) => send(&mut stream, &error(&e.to_string())).await?,
+                    }
+                }
+                piece = next_piece(&mut turn), if turn.is_some() => match piece {
+                    Some(piece) => {
+                        if let Some(turn) = turn.as_mut().filter(|turn| turn.first_token) {
+                            self.metrics.observe_first_token(turn.start.elapsed());
+                            turn.first_token = false;
+                        }
+                        send(&mut stream, &CompletionResponse::TOKEN { message: piece }).await?;
+                    }
+                    None => {
+                        let Turn { finished, session_params, .. } = turn.take().unwrap();
+                        match finished.await {
+                            Ok(Ok(completion)) => {
+                                // a turn that timed out while queued evaluated nothing, the history stays as it was
+                                if completion.n_past > 0 {
+                                    history = History {
+                                        tokens: completion.tokens.clone(),
+                                        session_params,
+                                    };
+                                }
+                                send(&mut stream, &CompletionResponse::from(completion)).await?;
+                            }
+                            Ok(Err(e)) => send(&mut stream, &error(&e)).await?,
+                            Err(_) => {
+                                send(&mut stream, &error("Unable to complete: Thread panicked")).await?
+                            }
+                        }
+                    }
+                },
+            }
+        }
+        event(
+            Level::Info,
+            "chat_closed",
+            json!({ "request_id": self.request_id, "turns": turns }),
+        );
+        Ok(())
+    }
+}
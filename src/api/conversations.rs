@@ -0,0 +1,401 @@
+use chrono::{SecondsFormat, Utc};
+use rocket::{
+    http::Status,
+    serde::json::{from_str, to_string, Value},
+    tokio::{
+        fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, write},
+        sync::Mutex,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::{io::ErrorKind, path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+use crate::{
+    api::auth::{ApiKey, Scope},
+    engine::models::SessionParams,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    SYSTEM,
+    USER,
+    ASSISTANT,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+    #[serde(default = "now")]
+    pub created: String,
+}
+
+// how messages are laid out in the prompt
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatFormat {
+    pub system_prefix: String,
+    pub user_prefix: String,
+    pub assistant_prefix: String,
+    pub separator: String,
+}
+
+impl Default for ChatFormat {
+    fn default() -> Self {
+        ChatFormat {
+            system_prefix: String::new(),
+            user_prefix: "User:".to_owned(),
+            assistant_prefix: "Assistant:".to_owned(),
+            separator: "\n".to_owned(),
+        }
+    }
+}
+
+impl ChatFormat {
+    fn prefix(&self, role: Role) -> &str {
+        match role {
+            Role::SYSTEM => &self.system_prefix,
+            Role::USER => &self.user_prefix,
+            Role::ASSISTANT => &self.assistant_prefix,
+        }
+    }
+
+    // every message followed by the assistant prefix, ready for the next assistant turn
+    pub fn render(&self, messages: &[Message]) -> String {
+        let mut prompt = String::new();
+        for message in messages {
+            let prefix = self.prefix(message.role);
+            prompt.push_str(prefix);
+            if !prefix.is_empty() && message.role != Role::SYSTEM {
+                prompt.push(' ');
+            }
+            prompt.push_str(&message.content);
+            prompt.push_str(&self.separator);
+        }
+        prompt.push_str(&self.assistant_prefix);
+        prompt
+    }
+
+    // stops the model from writing the user's next turn
+    pub fn stop(&self) -> String {
+        format!("{}{}", self.separator, self.user_prefix)
+    }
+}
+
+pub fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,
+    pub title: Option<String>,
+    #[serde(default)]
+    pub metadata: Value,
+    #[serde(default)]
+    pub format: ChatFormat,
+    pub created: String,
+    pub updated: String,
+    pub messages: Vec<Message>,
+    // context and token count of the session file saved after the last generated turn
+    #[serde(default)]
+    pub session_params: Option<SessionParams>,
+    #[serde(default)]
+    pub session_tokens: usize,
+    #[serde(default, skip_serializing)]
+    owner: Option<String>,
+}
+
+// stored separately so that serialising a conversation for clients leaves the key id out
+#[derive(Serialize, Deserialize)]
+struct StoredConversation {
+    owner: Option<String>,
+    #[serde(flatten)]
+    conversation: Conversation,
+}
+
+#[derive(Serialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub title: Option<String>,
+    pub metadata: Value,
+    pub created: String,
+    pub updated: String,
+    pub messages: usize,
+}
+
+// conversations as JSON files in the data directory, next to their session files
+#[derive(Clone)]
+pub struct Conversations {
+    dir: Option<PathBuf>,
+    // serialises read-modify-write cycles on the files
+    lock: Arc<Mutex<()>>,
+}
+
+impl Conversations {
+    pub fn new(data_dir: Option<PathBuf>) -> Conversations {
+        Conversations {
+            dir: data_dir.map(|dir| dir.join("conversations")),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn dir(&self) -> Result<&PathBuf, (Status, String)> {
+        self.dir.as_ref().ok_or_else(|| {
+            (
+                Status::NotFound,
+                "Conversations are disabled, start the server with --data-dir".to_owned(),
+            )
+        })
+    }
+
+    // ids are generated by the server, anything else could point outside the directory
+    fn path(&self, id: &str, extension: &str) -> Result<PathBuf, (Status, String)> {
+        let dir = self.dir()?;
+        if Uuid::parse_str(id).is_err() {
+            return Err((Status::NotFound, "No conversation with this id".to_owned()));
+        }
+        Ok(dir.join(format!("{}.{}", id, extension)))
+    }
+
+    pub fn session_path(&self, id: &str) -> Result<PathBuf, (Status, String)> {
+        self.path(id, "session")
+    }
+
+    async fn read(&self, id: &str) -> Result<StoredConversation, (Status, String)> {
+        let path = self.path(id, "json")?;
+        match read_to_string(&path).await {
+            Ok(contents) => from_str(&contents).map_err(|e| {
+                (
+                    Status::InternalServerError,
+                    format!("Invalid conversation file: {}", e),
+                )
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err((Status::NotFound, "No conversation with this id".to_owned()))
+            }
+            Err(e) => Err((
+                Status::InternalServerError,
+                format!("Unable to read conversation: {}", e),
+            )),
+        }
+    }
+
+    async fn write(&self, conversation: &Conversation) -> Result<(), (Status, String)> {
+        let dir = self.dir()?;
+        create_dir_all(dir).await.map_err(|e| {
+            (
+                Status::InternalServerError,
+                format!("Unable to create data directory: {}", e),
+            )
+        })?;
+        let stored = StoredConversation {
+            owner: conversation.owner.clone(),
+            conversation: conversation.clone(),
+        };
+        let contents = to_string(&stored).map_err(|e| {
+            (
+                Status::InternalServerError,
+                format!("Unable to serialise conversation: {}", e),
+            )
+        })?;
+        // renamed into place so that a crash never leaves a partly written conversation
+        let path = self.path(&conversation.id, "json")?;
+        let temp = self.path(&conversation.id, "json.tmp")?;
+        let written = match write(&temp, contents).await {
+            Ok(()) => rename(&temp, &path).await,
+            Err(e) => Err(e),
+        };
+        written.map_err(|e| {
+            (
+                Status::InternalServerError,
+                format!("Unable to write conversation: {}", e),
+            )
+        })
+    }
+
+    // only the key that created the conversation or an admin may use it
+    fn check_owner(stored: &StoredConversation, api_key: &ApiKey) -> Result<(), (Status, String)> {
+        if stored.owner != api_key.id() && !api_key.allows(Scope::ADMIN) {
+            return Err((
+                Status::Forbidden,
+                "API key is not permitted to perform this operation".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn create(
+        &self,
+        api_key: &ApiKey,
+        title: Option<String>,
+        metadata: Value,
+        format: ChatFormat,
+        messages: Vec<Message>,
+    ) -> Result<Conversation, (Status, String)> {
+        let created = now();
+        let conversation = Conversation {
+            id: Uuid::new_v4().to_string(),
+            title,
+            metadata,
+            format,
+            created: created.clone(),
+            updated: created,
+            messages,
+            session_params: None,
+            session_tokens: 0,
+            owner: api_key.id(),
+        };
+        let _lock = self.lock.lock().await;
+        self.write(&conversation).await?;
+        Ok(conversation)
+    }
+
+    pub async fn list(
+        &self,
+        api_key: &ApiKey,
+    ) -> Result<Vec<ConversationSummary>, (Status, String)> {
+        let dir = self.dir()?;
+        let mut entries = match read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err((
+                    Status::InternalServerError,
+                    format!("Unable to read data directory: {}", e),
+                ))
+            }
+        };
+        let mut res = Vec::new();
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let id = match (path.extension(), path.file_stem().and_then(|s| s.to_str())) {
+                (Some(extension), Some(id)) if extension == "json" => id.to_owned(),
+                _ => continue,
+            };
+            if let Ok(stored) = self.read(&id).await {
+                if Self::check_owner(&stored, api_key).is_ok() {
+                    let conversation = stored.conversation;
+                    res.push(ConversationSummary {
+                        id: conversation.id,
+                        title: conversation.title,
+                        metadata: conversation.metadata,
+                        created: conversation.created,
+                        updated: conversation.updated,
+                        messages: conversation.messages.len(),
+                    });
+                }
+            }
+        }
+        res.sort_by(|a, b| b.updated.cmp(&a.updated));
+        Ok(res)
+    }
+
+    pub async fn get(&self, id: &str, api_key: &ApiKey) -> Result<Conversation, (Status, String)> {
+        let stored = self.read(id).await?;
+        Self::check_owner(&stored, api_key)?;
+        let mut conversation = stored.conversation;
+        conversation.owner = stored.owner;
+        Ok(conversation)
+    }
+
+    pub async fn delete(&self, id: &str, api_key: &ApiKey) -> Result<(), (Status, String)> {
+        let _lock = self.lock.lock().await;
+        self.get(id, api_key).await?;
+        remove_file(self.path(id, "json")?).await.map_err(|e| {
+            (
+                Status::InternalServerError,
+                format!("Unable to delete conversation: {}", e),
+            )
+        })?;
+        remove_file(self.session_path(id)?).await.ok();
+        Ok(())
+    }
+
+    // update runs with the conversation locked against other writers
+    pub async fn update(
+        &self,
+        id: &str,
+        api_key: &ApiKey,
+        update: impl FnOnce(&mut Conversation),
+    ) -> Result<Conversation, (Status, String)> {
+        let _lock = self.lock.lock().await;
+        let mut conversation = self.get(id, api_key).await?;
+        update(&mut conversation);
+        conversation.updated = now();
+        self.write(&conversation).await?;
+        Ok(conversation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_owned(),
+            created: now(),
+        }
+    }
+
+    #[test]
+    fn render_default_format() {
+        let messages = [
+            message(Role::SYSTEM, "Be brief."),
+            message(Role::USER, "Hi"),
+            message(Role::ASSISTANT, "Hello."),
+            message(Role::USER, "How are you?"),
+        ];
+        assert_eq!(
+            ChatFormat::default().render(&messages),
+            "Be brief.\nUser: Hi\nAssistant: Hello.\nUser: How are you?\nAssistant:"
+        );
+        assert_eq!(ChatFormat::default().render(&[]), "Assistant:");
+        assert_eq!(ChatFormat::default().stop(), "\nUser:");
+    }
+
+    #[test]
+    fn render_custom_format() {
+        let format = ChatFormat {
+            system_prefix: "<<SYS>>".to_owned(),
+            user_prefix: "[INST]".to_owned(),
+            assistant_prefix: "[/INST]".to_owned(),
+            separator: "\n\n".to_owned(),
+        };
+        let messages = [
+            message(Role::SYSTEM, "Be brief."),
+            message(Role::USER, "Hi"),
+        ];
+        // no space follows the system prefix
+        assert_eq!(
+            format.render(&messages),
+            "<<SYS>>Be brief.\n\n[INST] Hi\n\n[/INST]"
+        );
+        assert_eq!(format.stop(), "\n\n[INST]");
+    }
+
+    #[test]
+    fn render_without_prefixes() {
+        let format = ChatFormat {
+            system_prefix: String::new(),
+            user_prefix: String::new(),
+            assistant_prefix: String::new(),
+            separator: "\n".to_owned(),
+        };
+        let messages = [message(Role::USER, "Hi"), message(Role::ASSISTANT, "Hello")];
+        assert_eq!(format.render(&messages), "Hi\nHello\n");
+    }
+
+    #[test]
+    fn format_fields_default_separately() {
+        let format: ChatFormat =
+            rocket::serde::json::from_str(r#"{ "user_prefix": "Q:" }"#).unwrap();
+        assert_eq!(format.user_prefix, "Q:");
+        assert_eq!(format.assistant_prefix, "Assistant:");
+        assert_eq!(format.separator, "\n");
+    }
+}
@@ -0,0 +1,79 @@
+use rocket::http::Status;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::api::auth::{ApiKey, Scope};
+
+struct Job {
+    owner: Option<String>,
+    cancelled: Arc<AtomicBool>,
+}
+
+// in-flight completions by request id, so they can be cancelled from another request
+#[derive(Clone, Default)]
+pub struct Jobs {
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+}
+
+impl Jobs {
+    // returns None if a job with the same id is still running
+    pub fn start(&self, id: &str, owner: Option<String>) -> Option<JobHandle> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.contains_key(id) {
+            return None;
+        }
+        let cancelled = Arc::new(AtomicBool::new(false));
+        jobs.insert(
+            id.to_owned(),
+            Job {
+                owner,
+                cancelled: cancelled.clone(),
+            },
+        );
+        Some(JobHandle {
+            id: id.to_owned(),
+            jobs: self.jobs.clone(),
+            cancelled,
+        })
+    }
+
+    // only the key that started the job or an admin may cancel it
+    pub fn cancel(&self, id: &str, api_key: &ApiKey) -> Result<(), Status> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id).ok_or(Status::NotFound)?;
+        if job.owner != api_key.key && !api_key.allows(Scope::ADMIN) {
+            return Err(Status::Forbidden);
+        }
+        job.cancelled.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// unregisters the job when dropped, cancelling it if the generation is still running
+pub struct JobHandle {
+    id: String,
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl JobHandle {
+    pub fn cancelled(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        self.cancel();
+        self.jobs.lock().unwrap().remove(&self.id);
+    }
+}
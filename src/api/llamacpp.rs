@@ -0,0 +1,299 @@
+use rocket::serde::json::{from_value, json, serde_json::Map, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::inference::{Completion, FinishReason, GenerationParams, Timings},
+    sys::llama_token,
+};
+
+// the request and response shapes of the server example that ships with llama.cpp
+
+// sampling options share their names with GenerationParams apart from n_predict,
+// ignore_eos, logit_bias, n_probs and penalize_nl are not supported and ignored
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub stream: bool,
+    // prompt tokens kept when the prompt does not fit the context, -1 for all of them
+    #[serde(default)]
+    pub n_keep: i64,
+    #[serde(flatten)]
+    pub options: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenizeRequest {
+    pub content: String,
+}
+
+#[derive(Serialize)]
+pub struct TokenizeResponse {
+    pub tokens: Vec<llama_token>,
+}
+
+#[derive(Deserialize)]
+pub struct DetokenizeRequest {
+    pub tokens: Vec<llama_token>,
+}
+
+#[derive(Serialize)]
+pub struct DetokenizeResponse {
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingRequest {
+    pub content: String,
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingResponse {
+    pub embedding: Vec<f32>,
+}
+
+// n_predict defaults to generating until the context is full, as it does upstream
+pub fn generation_params(options: &Map<String, Value>) -> Result<GenerationParams, String> {
+    let mut params = options.clone();
+    let max_tokens = match params.remove("n_predict") {
+        Some(n_predict) if n_predict.as_i64().is_some_and(|n| n < 0) => json!(usize::MAX),
+        Some(n_predict) => n_predict,
+        None => json!(usize::MAX),
+    };
+    params.insert("max_tokens".to_owned(), max_tokens);
+    from_value(Value::Object(params)).map_err(|e| format!("Invalid options: {}", e))
+}
+
+// a prompt that does not fit keeps its first n_keep tokens followed by as many of its last tokens
+// as fill half of what remains, leaving the other half for generation
+// returns whether the prompt was truncated
+pub fn truncate_prompt(tokens: &mut Vec<llama_token>, n_keep: i64, n_ctx: usize) -> bool {
+    // generate refuses prompts without room for a few tokens
+    if tokens.len() + 4 <= n_ctx {
+        return false;
+    }
+    let n_keep = if n_keep < 0 {
+        tokens.len()
+    } else {
+        n_keep as usize
+    }
+    .min(n_ctx.saturating_sub(8))
+    // llama_eval needs the beginning of sentence token first
+    .max(1)
+    .min(tokens.len());
+    let n_left = (n_ctx.saturating_sub(n_keep) / 2).min(tokens.len() - n_keep);
+    tokens.drain(n_keep..tokens.len() - n_left);
+    true
+}
+
+// the server example's timings, in milliseconds
+#[derive(Serialize)]
+struct ServerTimings {
+    prompt_n: usize,
+    prompt_ms: f64,
+    prompt_per_token_ms: f64,
+    prompt_per_second: f64,
+    predicted_n: usize,
+    predicted_ms: f64,
+    predicted_per_token_ms: f64,
+    predicted_per_second: f64,
+}
+
+fn per_token(ms: f64, n: usize) -> f64 {
+    if n > 0 {
+        ms / n as f64
+    } else {
+        0.0
+    }
+}
+
+impl From<&Timings> for ServerTimings {
+    fn from(timings: &Timings) -> Self {
+        let prompt_n = timings.prompt_tokens - timings.cached_tokens;
+        ServerTimings {
+            prompt_n,
+            prompt_ms: timings.prompt_eval_ms,
+            prompt_per_token_ms: per_token(timings.prompt_eval_ms, prompt_n),
+            prompt_per_second: timings.prompt_tokens_per_second,
+            predicted_n: timings.generated_tokens,
+            predicted_ms: timings.eval_ms,
+            predicted_per_token_ms: per_token(timings.eval_ms, timings.generated_tokens),
+            predicted_per_second: timings.generated_tokens_per_second,
+        }
+    }
+}
+
+// echoed back with the result, the seed is filled in once it is known
+pub fn generation_settings(
+    params: &GenerationParams,
+    model: Option<&str>,
+    n_ctx: Option<i32>,
+    n_keep: i64,
+    stream: bool,
+) -> Value {
+    let sampling = &params.sampling;
+    json!({
+        "model": model,
+        "n_ctx": n_ctx,
+        "n_predict": if params.max_tokens == usize::MAX { -1 } else { params.max_tokens as i64 },
+        "n_keep": n_keep,
+        "stream": stream,
+        "stop": params.stop,
+        "seed": sampling.seed,
+        "temperature": sampling.temperature,
+        "top_k": sampling.top_k,
+        "top_p": sampling.top_p,
+        "tfs_z": sampling.tfs_z,
+        "typical_p": sampling.typical_p,
+        "repeat_last_n": sampling.repeat_last_n,
+        "repeat_penalty": sampling.repeat_penalty,
+        "presence_penalty": sampling.presence_penalty,
+        "frequency_penalty": sampling.frequency_penalty,
+        "mirostat": sampling.mirostat,
+        "mirostat_tau": sampling.mirostat_tau,
+        "mirostat_eta": sampling.mirostat_eta,
+    })
+}
+
+// sent once generation stopped
+#[derive(Serialize)]
+pub struct CompletionResult {
+    model: Option<String>,
+    prompt: String,
+    generation_settings: Value,
+    stopped_eos: bool,
+    stopped_word: bool,
+    stopped_limit: bool,
+    stopping_word: String,
+    tokens_predicted: usize,
+    tokens_evaluated: usize,
+    tokens_cached: usize,
+    truncated: bool,
+    timings: ServerTimings,
+}
+
+impl CompletionResult {
+    pub fn new(
+        completion: &Completion,
+        model: Option<String>,
+        prompt: String,
+        mut generation_settings: Value,
+        truncated: bool,
+    ) -> CompletionResult {
+        generation_settings["seed"] = json!(completion.seed);
+        let stopped = matches!(completion.finish_reason, FinishReason::STOP);
+        CompletionResult {
+            model,
+            prompt,
+            generation_settings,
+            stopped_eos: stopped && completion.stop_word.is_none(),
+            stopped_word: completion.stop_word.is_some(),
+            stopped_limit: matches!(completion.finish_reason, FinishReason::LENGTH),
+            stopping_word: completion.stop_word.clone().unwrap_or_default(),
+            tokens_predicted: completion.timings.generated_tokens,
+            tokens_evaluated: completion.timings.prompt_tokens,
+            tokens_cached: completion.n_past,
+            truncated,
+            timings: ServerTimings::from(&completion.timings),
+        }
+    }
+}
+
+// streamed as a piece of content per event, the last one has stop set and the result
+#[derive(Serialize)]
+pub struct CompletionResponse {
+    content: String,
+    stop: bool,
+    #[serde(flatten)]
+    result: Option<CompletionResult>,
+}
+
+impl CompletionResponse {
+    pub fn token(content: String) -> CompletionResponse {
+        CompletionResponse {
+            content,
+            stop: false,
+            result: None,
+        }
+    }
+
+    // content is empty when the text was streamed
+    pub fn done(content: String, result: CompletionResult) -> CompletionResponse {
+        CompletionResponse {
+            content,
+            stop: true,
+            result: Some(result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(len: usize) -> Vec<llama_token> {
+        (0..len as llama_token).collect()
+    }
+
+    #[test]
+    fn prompt_that_fits_is_kept() {
+        let mut tokens = prompt(28);
+        assert!(!truncate_prompt(&mut tokens, 4, 32));
+        assert_eq!(tokens, prompt(28));
+    }
+
+    #[test]
+    fn truncation_keeps_the_start_and_the_end() {
+        let mut tokens = prompt(40);
+        assert!(truncate_prompt(&mut tokens, 4, 32));
+        // 4 kept, then half of the remaining 28
+        assert_eq!(tokens[..4], [0, 1, 2, 3]);
+        assert_eq!(tokens[4..], prompt(40)[26..]);
+    }
+
+    #[test]
+    fn n_keep_near_the_context_size() {
+        let mut tokens = prompt(40);
+        assert!(truncate_prompt(&mut tokens, 23, 32));
+        assert_eq!(tokens[..23], prompt(23)[..]);
+        assert_eq!(tokens[23..], [36, 37, 38, 39]);
+        for n_keep in [24, 25, 31, 32, 100, -1] {
+            let mut tokens = prompt(40);
+            assert!(truncate_prompt(&mut tokens, n_keep, 32));
+            // at most n_ctx - 8 tokens are kept from the start, so a few are always left to generate
+            assert_eq!(tokens[..24], prompt(24)[..]);
+            assert_eq!(tokens[24..], [36, 37, 38, 39]);
+            assert!(tokens.len() + 4 <= 32);
+        }
+    }
+
+    #[test]
+    fn truncation_leaves_room_to_generate() {
+        for len in 29..64 {
+            for n_keep in -1..40 {
+                let mut tokens = prompt(len);
+                assert!(truncate_prompt(&mut tokens, n_keep, 32));
+                assert!(tokens.len() + 4 <= 32, "len {} n_keep {}", len, n_keep);
+            }
+        }
+    }
+
+    #[test]
+    fn tiny_context() {
+        let mut tokens = prompt(10);
+        assert!(truncate_prompt(&mut tokens, 4, 6));
+        assert_eq!(tokens, [0, 8, 9]);
+        let mut tokens = prompt(1);
+        assert!(truncate_prompt(&mut tokens, 0, 3));
+        assert_eq!(tokens, [0]);
+    }
+
+    #[test]
+    fn beginning_of_sentence_is_kept() {
+        let mut tokens = prompt(40);
+        assert!(truncate_prompt(&mut tokens, 0, 32));
+        // the first token, then half of the remaining 31
+        assert_eq!(tokens[0], 0);
+        assert_eq!(tokens[1..], prompt(40)[25..]);
+    }
+}
@@ -0,0 +1,143 @@
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    request::{FromRequest, Outcome},
+    serde::json::{json, Value},
+    Data, Request, Response,
+};
+use std::{
+    io::{stderr, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+use uuid::Uuid;
+
+use crate::config::LogLevel;
+
+static LOG_PROMPTS: AtomicBool = AtomicBool::new(false);
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::OFF => LevelFilter::Off,
+            LogLevel::ERROR => LevelFilter::Error,
+            LogLevel::WARN => LevelFilter::Warn,
+            LogLevel::INFO => LevelFilter::Info,
+            LogLevel::DEBUG => LevelFilter::Debug,
+            LogLevel::TRACE => LevelFilter::Trace,
+        }
+    }
+}
+
+fn write_line(level: Level, mut fields: Value) {
+    fields["timestamp"] = json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+    fields["level"] = json!(level.as_str());
+    // one write per line so concurrent requests do not interleave
+    let line = format!("{}\n", fields);
+    stderr().lock().write_all(line.as_bytes()).ok();
+}
+
+// renders every log record, including Rocket's own, as a JSON line on stderr
+struct JsonLogger;
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            write_line(
+                record.level(),
+                json!({ "target": record.target(), "message": record.args().to_string() }),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// must run before Rocket is launched, otherwise Rocket installs its own logger
+pub fn init(level: LogLevel, log_prompts: bool) {
+    log::set_boxed_logger(Box::new(JsonLogger)).ok();
+    log::set_max_level(level.into());
+    LOG_PROMPTS.store(log_prompts, Ordering::Relaxed);
+}
+
+pub fn log_prompts() -> bool {
+    LOG_PROMPTS.load(Ordering::Relaxed)
+}
+
+// logs a named event with its fields merged into the JSON line
+pub fn event(level: Level, event: &str, mut fields: Value) {
+    if level <= log::max_level() {
+        fields["event"] = json!(event);
+        write_line(level, fields);
+    }
+}
+
+// taken from the X-Request-Id header if the client sent a usable one, generated otherwise
+pub struct RequestId {
+    pub id: String,
+    pub start: Instant,
+}
+
+impl RequestId {
+    fn from_request(request: &Request<'_>) -> RequestId {
+        let id = request
+            .headers()
+            .get_one("X-Request-Id")
+            .filter(|id| !id.is_empty() && id.len() <= 128)
+            .filter(|id| id.chars().all(|c| c.is_ascii_graphic()))
+            .map(str::to_owned)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        RequestId {
+            id,
+            start: Instant::now(),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(|| RequestId::from_request(request)))
+    }
+}
+
+// assigns request ids, echoes them in X-Request-Id and logs every response
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request logging",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestId::from_request(request));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = request.local_cache(|| RequestId::from_request(request));
+        response.set_header(Header::new("X-Request-Id", request_id.id.clone()));
+        event(
+            Level::Info,
+            "request",
+            json!({
+                "request_id": request_id.id,
+                "method": request.method().as_str(),
+                "uri": request.uri().to_string(),
+                "status": response.status().code,
+                "duration_ms": request_id.start.elapsed().as_secs_f64() * 1000.0,
+            }),
+        );
+    }
+}
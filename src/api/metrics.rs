@@ -0,0 +1,189 @@
+use prometheus::{
+    core::Collector, exponential_buckets, Encoder, Gauge, Histogram, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Request, Response,
+};
+use std::time::Duration;
+
+use crate::engine::inference::Timings;
+
+// clones share the same registry
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    pub queue_depth: IntGauge,
+    prompt_tokens: IntCounter,
+    generated_tokens: IntCounter,
+    prompt_tokens_per_second: Gauge,
+    generated_tokens_per_second: Gauge,
+    time_to_first_token: Histogram,
+    prompt_eval_seconds: Histogram,
+    eval_seconds: Histogram,
+    sample_seconds: Histogram,
+    model_load_seconds: HistogramVec,
+    loaded_model: IntGaugeVec,
+    kv_cache_tokens: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("llama".to_owned()), None).unwrap();
+        let seconds_buckets = exponential_buckets(0.01, 2.0, 14).unwrap();
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and status"),
+                &["route", "status"],
+            )
+            .unwrap(),
+            queue_depth: IntGauge::new("queue_depth", "Requests waiting for the context").unwrap(),
+            prompt_tokens: IntCounter::new("prompt_tokens_total", "Prompt tokens evaluated")
+                .unwrap(),
+            generated_tokens: IntCounter::new("generated_tokens_total", "Tokens generated")
+                .unwrap(),
+            prompt_tokens_per_second: Gauge::new(
+                "prompt_tokens_per_second",
+                "Prompt evaluation speed of the last completion",
+            )
+            .unwrap(),
+            generated_tokens_per_second: Gauge::new(
+                "generated_tokens_per_second",
+                "Generation speed of the last completion",
+            )
+            .unwrap(),
+            time_to_first_token: Histogram::with_opts(
+                HistogramOpts::new(
+                    "time_to_first_token_seconds",
+                    "Time from accepting a completion to its first generated token",
+                )
+                .buckets(seconds_buckets.clone()),
+            )
+            .unwrap(),
+            prompt_eval_seconds: Histogram::with_opts(
+                HistogramOpts::new("prompt_eval_seconds", "Time spent evaluating prompts")
+                    .buckets(seconds_buckets.clone()),
+            )
+            .unwrap(),
+            eval_seconds: Histogram::with_opts(
+                HistogramOpts::new("eval_seconds", "Time spent evaluating generated tokens")
+                    .buckets(seconds_buckets.clone()),
+            )
+            .unwrap(),
+            sample_seconds: Histogram::with_opts(
+                HistogramOpts::new("sample_seconds", "Time spent sampling tokens")
+                    .buckets(seconds_buckets.clone()),
+            )
+            .unwrap(),
+            model_load_seconds: HistogramVec::new(
+                HistogramOpts::new("model_load_seconds", "Time spent loading models")
+                    .buckets(seconds_buckets),
+                &["model"],
+            )
+            .unwrap(),
+            loaded_model: IntGaugeVec::new(
+                Opts::new("loaded_model", "Currently loaded model"),
+                &["model"],
+            )
+            .unwrap(),
+            kv_cache_tokens: IntGauge::new("kv_cache_tokens", "Tokens in the KV cache").unwrap(),
+            registry,
+        };
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.prompt_tokens.clone()),
+            Box::new(metrics.generated_tokens.clone()),
+            Box::new(metrics.prompt_tokens_per_second.clone()),
+            Box::new(metrics.generated_tokens_per_second.clone()),
+            Box::new(metrics.time_to_first_token.clone()),
+            Box::new(metrics.prompt_eval_seconds.clone()),
+            Box::new(metrics.eval_seconds.clone()),
+            Box::new(metrics.sample_seconds.clone()),
+            Box::new(metrics.model_load_seconds.clone()),
+            Box::new(metrics.loaded_model.clone()),
+            Box::new(metrics.kv_cache_tokens.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn observe_model_load(&self, model: &str, duration: Duration) {
+        self.model_load_seconds
+            .with_label_values(&[model])
+            .observe(duration.as_secs_f64());
+        self.loaded_model.reset();
+        self.loaded_model.with_label_values(&[model]).set(1);
+    }
+
+    pub fn observe_model_unload(&self) {
+        self.loaded_model.reset();
+        self.kv_cache_tokens.set(0);
+    }
+
+    pub fn observe_first_token(&self, duration: Duration) {
+        self.time_to_first_token.observe(duration.as_secs_f64());
+    }
+
+    // replaces llama_print_timings, which only writes to stderr
+    pub fn observe_completion(&self, timings: &Timings) {
+        self.prompt_tokens
+            .inc_by((timings.prompt_tokens - timings.cached_tokens) as u64);
+        self.generated_tokens
+            .inc_by(timings.generated_tokens as u64);
+        self.prompt_eval_seconds
+            .observe(timings.prompt_eval_ms / 1000.0);
+        self.eval_seconds.observe(timings.eval_ms / 1000.0);
+        self.sample_seconds.observe(timings.sample_ms / 1000.0);
+        if timings.prompt_tokens_per_second > 0.0 {
+            self.prompt_tokens_per_second
+                .set(timings.prompt_tokens_per_second);
+        }
+        if timings.generated_tokens_per_second > 0.0 {
+            self.generated_tokens_per_second
+                .set(timings.generated_tokens_per_second);
+        }
+    }
+
+    pub fn set_kv_cache_tokens(&self, tokens: i64) {
+        self.kv_cache_tokens.set(tokens);
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+// counts every response by matched route and status
+pub struct RequestCounter;
+
+#[rocket::async_trait]
+impl Fairing for RequestCounter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let Some(metrics) = request.rocket().state::<Metrics>() {
+            let route = request
+                .route()
+                .map(|route| route.uri.to_string())
+                .unwrap_or_else(|| "unmatched".to_owned());
+            metrics
+                .requests
+                .with_label_values(&[&route, &response.status().code.to_string()])
+                .inc();
+        }
+    }
+}
@@ -0,0 +1,898 @@
+mod auth;
+mod batch;
+mod chat;
+mod conversations;
+mod jobs;
+mod llamacpp;
+pub mod logging;
+mod metrics;
+mod ollama;
+pub mod ratelimit;
+mod routes;
+
+use auth::{ApiKeys, Scope};
+use batch::{BatchProgress, Batches};
+use conversations::{Conversation, ConversationSummary, Conversations};
+use jobs::{JobHandle, Jobs};
+use log::{error, info, warn, Level};
+use logging::{event, RequestLogger};
+use metrics::{Metrics, RequestCounter};
+use ratelimit::{Client, RateLimiter};
+use rocket::{
+    http::Status,
+    response::status,
+    serde::json::{json, Json, Value},
+    tokio::{
+        sync::{mpsc, oneshot, Mutex, RwLock},
+        task::{spawn_blocking, JoinHandle},
+        time::timeout_at,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CStr,
+    sync::Arc,
+    thread::available_parallelism,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{read_model_params, LoadParams, ModelParams, CLI},
+    engine::{
+        guidance::GuidanceContext,
+        inference::{
+            self, generate, Choice, Completion, FinishReason, GenerationParams, Interrupt, Limits,
+            Timings,
+        },
+        init, load_draft, load_model,
+        models::{self, KeepAlive, LoadedModel, ModelDirectory, ModelInfo, SessionParams},
+        restore_session, save_session, tokenize_text, LlamaContext, ModelContext, Session,
+    },
+    sys::{llama_print_system_info, llama_token, llama_token_bos},
+};
+
+struct MainState {
+    load_params: LoadParams,
+    ctx: Option<Arc<Mutex<ModelContext>>>,
+    current_model: Option<String>,
+    loaded_model: Option<LoadedModel>,
+    load_time: Option<Duration>,
+    // idle time after which the model is unloaded, none keeps it loaded
+    keep_alive: Option<Duration>,
+    last_used: Instant,
+    // held while a model is loaded or unloaded, so only one context exists at a time
+    loading: Arc<Mutex<()>>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "event")]
+#[serde(rename_all = "lowercase")]
+enum ModelEventRequest {
+    LOAD {
+        message: String,
+        #[serde(default)]
+        params: ModelParams,
+    },
+    UNLOAD,
+    LIST,
+    CURRENT,
+    INFO,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "response")]
+#[serde(rename_all = "lowercase")]
+enum ModelEventResponse {
+    OK {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    #[serde(rename(serialize = "ok"))]
+    OKModels { message: Vec<ModelInfo> },
+    #[serde(rename(serialize = "ok"))]
+    OKInfo { message: LoadedModel },
+    #[serde(rename(serialize = "ok"))]
+    OKBatch(BatchProgress),
+    #[serde(rename(serialize = "ok"))]
+    OKConversation { message: Conversation },
+    #[serde(rename(serialize = "ok"))]
+    OKConversations { message: Vec<ConversationSummary> },
+    ERROR {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
+// loads a model from the model directory in place of the current one, and returns its worker
+// params from the request take precedence over the sidecar file, which takes precedence over the command line
+async fn load_by_name(
+    state: &RwLock<MainState>,
+    model_dir: &ModelDirectory,
+    metrics: &Metrics,
+    request_id: &str,
+    name: &str,
+    params: ModelParams,
+) -> Result<Worker, (Status, String)> {
+    let loading = state.read().await.loading.clone();
+    let _loading = loading.lock().await;
+    let model_info = model_dir
+        .find(name)
+        .ok_or_else(|| (Status::BadRequest, "Invalid model name".to_owned()))?;
+    let load_params = state.read().await.load_params.clone();
+    let path_to_model = load_params.path_to_model_dir.join(name);
+    let params = match read_model_params(&path_to_model).await {
+        Ok(sidecar_params) => params
+            .or(sidecar_params)
+            .or(ModelParams::from(&load_params)),
+        Err(e) => {
+            event(
+                Level::Error,
+                "model_params_invalid",
+                json!({ "request_id": request_id, "model": name, "error": e }),
+            );
+            return Err((Status::BadRequest, e));
+        }
+    };
+    let keep_alive = match &params.idle_timeout {
+        Some(idle_timeout) => idle_timeout
+            .duration()
+            .map_err(|e| (Status::BadRequest, e))?,
+        None => None,
+    };
+    let missing_file = |name: &str| {
+        (
+            Status::BadRequest,
+            format!("File not found in model directory: {}", name),
+        )
+    };
+    let mut lora_adapters = Vec::new();
+    for name in params.lora_adapters.iter().flatten() {
+        lora_adapters.push(model_dir.resolve(name).ok_or_else(|| missing_file(name))?);
+    }
+    let lora_base = match &params.lora_base {
+        Some(name) => Some(model_dir.resolve(name).ok_or_else(|| missing_file(name))?),
+        None => None,
+    };
+    let draft_model = match &params.draft_model {
+        Some(name) => Some(model_dir.resolve(name).ok_or_else(|| missing_file(name))?),
+        None => None,
+    };
+    let (sender, recv) =
+        oneshot::channel::<Result<(Arc<Mutex<ModelContext>>, LoadedModel), String>>();
+    let load_start = Instant::now();
+    let wrapped_ctx = state.read().await.ctx.clone();
+    rocket::tokio::spawn(async move {
+        if let Some(existing_wrapped_ctx) = wrapped_ctx {
+            existing_wrapped_ctx.lock().await.free();
+        }
+        let res = load_model(
+            path_to_model.to_str().unwrap(),
+            &params,
+            &lora_adapters,
+            lora_base.as_deref(),
+            load_params.n_threads,
+        )
+        .and_then(|(ctx, ctx_params)| {
+            // contexts loaded before a failure are freed as they are dropped
+            let draft = match &draft_model {
+                Some(path) => Some(load_draft(path, &params, &ctx, load_params.n_threads)?),
+                None => None,
+            };
+            let guidance = if params.guidance.unwrap_or(false) {
+                match load_model(
+                    path_to_model.to_str().unwrap(),
+                    &ModelParams {
+                        draft_model: None,
+                        ..params.clone()
+                    },
+                    &lora_adapters,
+                    lora_base.as_deref(),
+                    load_params.n_threads,
+                ) {
+                    Ok((guidance_ctx, _)) => Some(GuidanceContext {
+                        ctx: guidance_ctx,
+                        kv_tokens: Vec::new(),
+                    }),
+                    Err(_) => return Err("Unable to load guidance context".to_owned()),
+                }
+            } else {
+                None
+            };
+            let mut loaded_model = LoadedModel::new(
+                &ctx,
+                &model_info,
+                &ctx_params,
+                params.lora_adapters.clone().unwrap_or_default(),
+                params.lora_base.clone(),
+            );
+            if let Some(draft) = &draft {
+                loaded_model.draft_model = params.draft_model.clone();
+                loaded_model.draft_tokens = Some(draft.n_draft);
+            }
+            loaded_model.guidance = guidance.is_some();
+            Ok((
+                Arc::new(Mutex::new(ModelContext {
+                    ctx: Some(ctx),
+                    kv_tokens: Vec::new(),
+                    draft,
+                    guidance,
+                })),
+                loaded_model,
+            ))
+        });
+        sender.send(res).ok();
+    });
+    match recv.await {
+        Ok(Ok((wrapped_ctx, loaded_model))) => {
+            let load_time = load_start.elapsed();
+            metrics.observe_model_load(name, load_time);
+            event(
+                Level::Info,
+                "model_load",
+                json!({
+                    "request_id": request_id,
+                    "model": name,
+                    "duration_ms": load_time.as_secs_f64() * 1000.0,
+                }),
+            );
+            let mut state = state.write().await;
+            state.load_time = Some(load_time);
+            state.ctx = Some(wrapped_ctx);
+            state.loaded_model = Some(loaded_model);
+            state.current_model = Some(name.to_owned());
+            state.keep_alive = keep_alive;
+            state.last_used = Instant::now();
+            Worker::of(&state).map_err(|e| (Status::InternalServerError, e))
+        }
+        Ok(Err(e)) => {
+            event(
+                Level::Error,
+                "model_load_failed",
+                json!({
+                    "request_id": request_id,
+                    "model": name,
+                    "duration_ms": load_start.elapsed().as_secs_f64() * 1000.0,
+                    "error": e,
+                }),
+            );
+            // the previous context was freed before loading
+            metrics.observe_model_unload();
+            let mut state = state.write().await;
+            state.ctx = None;
+            state.current_model = None;
+            state.loaded_model = None;
+            Err((Status::InternalServerError, e))
+        }
+        Err(_) => {
+            event(
+                Level::Error,
+                "model_load_failed",
+                json!({
+                    "request_id": request_id,
+                    "model": name,
+                    "error": "Thread panicked",
+                }),
+            );
+            Err((
+                Status::InternalServerError,
+                "Unable to load model".to_owned(),
+            ))
+        }
+    }
+}
+
+// log_fields identify what asked for the unload in the model_unload event
+async fn unload_model(
+    state: &RwLock<MainState>,
+    metrics: &Metrics,
+    mut log_fields: Value,
+) -> Result<(), (Status, String)> {
+    let loading = state.read().await.loading.clone();
+    let _loading = loading.lock().await;
+    let wrapped_ctx = state
+        .write()
+        .await
+        .ctx
+        .take()
+        .ok_or_else(|| (Status::BadRequest, "No model loaded".to_owned()))?;
+    let (sender, recv) = oneshot::channel::<bool>();
+    let unload_start = Instant::now();
+    rocket::tokio::spawn(async move {
+        wrapped_ctx.lock().await.free();
+        sender.send(true).ok();
+    });
+    match recv.await {
+        Ok(true) => {
+            metrics.observe_model_unload();
+            let mut state = state.write().await;
+            log_fields["model"] = json!(state.current_model);
+            log_fields["duration_ms"] = json!(unload_start.elapsed().as_secs_f64() * 1000.0);
+            event(Level::Info, "model_unload", log_fields);
+            state.current_model = None;
+            state.loaded_model = None;
+            state.load_time = None;
+            Ok(())
+        }
+        Ok(false) => Err((
+            Status::InternalServerError,
+            "Unable to unload: Error freeing memory".to_owned(),
+        )),
+        Err(_) => Err((
+            Status::InternalServerError,
+            "Unable to unload: Thread panicked".to_owned(),
+        )),
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "response")]
+#[serde(rename_all = "lowercase")]
+enum CompletionResponse {
+    TOKEN {
+        message: String,
+    },
+    OK {
+        message: String,
+        finish_reason: FinishReason,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seed: Option<i32>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        choices: Vec<Choice>,
+        timings: Timings,
+    },
+}
+
+impl From<Completion> for CompletionResponse {
+    fn from(completion: Completion) -> Self {
+        CompletionResponse::OK {
+            message: completion.text,
+            finish_reason: completion.finish_reason,
+            seed: completion.seed,
+            choices: completion.choices,
+            timings: completion.timings,
+        }
+    }
+}
+
+// a request's keep_alive replaces the idle timeout of the loaded model
+async fn set_keep_alive(
+    state: &RwLock<MainState>,
+    keep_alive: Option<&KeepAlive>,
+) -> Result<(), (Status, String)> {
+    if let Some(keep_alive) = keep_alive {
+        let keep_alive = keep_alive.duration().map_err(|e| (Status::BadRequest, e))?;
+        let mut state = state.write().await;
+        state.keep_alive = keep_alive;
+        state.last_used = Instant::now();
+    }
+    Ok(())
+}
+
+// what a completion needs from MainState, owned so that batches can outlive their request
+#[derive(Clone)]
+struct Worker {
+    ctx: Arc<Mutex<ModelContext>>,
+    load_time: Duration,
+    n_threads: i32,
+    n_vocab: i32,
+    // what a session must have been saved with to be restored into this context
+    session_params: Option<SessionParams>,
+    // the context has no KV cache, so it can only tokenize
+    vocab_only: bool,
+}
+
+impl Worker {
+    async fn current(state: &RwLock<MainState>) -> Result<Worker, String> {
+        Worker::of(&*state.read().await)
+    }
+
+    fn of(state: &MainState) -> Result<Worker, String> {
+        Ok(Worker {
+            ctx: state
+                .ctx
+                .clone()
+                .ok_or_else(|| "No model loaded".to_owned())?,
+            load_time: state.load_time.unwrap_or_default(),
+            n_threads: state.load_params.n_threads,
+            n_vocab: state
+                .loaded_model
+                .as_ref()
+                .map_or(0, |loaded_model| loaded_model.n_vocab),
+            session_params: state.loaded_model.as_ref().map(LoadedModel::session_params),
+            vocab_only: state
+                .loaded_model
+                .as_ref()
+                .is_some_and(|loaded_model| loaded_model.params.vocab_only),
+        })
+    }
+
+    // llama_eval aborts the process on a context without a KV cache
+    fn check_inference(&self) -> Result<(), String> {
+        if self.vocab_only {
+            return Err("Model was loaded with vocab_only and can only tokenize".to_owned());
+        }
+        Ok(())
+    }
+
+    // llama_eval and llama_token_to_str do not check token ids
+    fn check_tokens(&self, tokens: &[llama_token]) -> Result<(), (Status, String)> {
+        match tokens
+            .iter()
+            .find(|&&token| token < 0 || token >= self.n_vocab)
+        {
+            Some(token) => Err((Status::BadRequest, format!("Invalid token {}", token))),
+            None => Ok(()),
+        }
+    }
+}
+
+// waits for the context, then generates on a blocking thread and sends text through the channel as it is produced
+// prompt turns the request into tokens once the context is available
+async fn start_completion(
+    worker: &Worker,
+    metrics: &Metrics,
+    prompt: impl FnOnce(&LlamaContext) -> Result<Vec<llama_token>, String>,
+    params: GenerationParams,
+    interrupt: Interrupt,
+    session: Option<Session>,
+) -> Result<
+    (
+        mpsc::UnboundedReceiver<String>,
+        JoinHandle<Result<Completion, String>>,
+    ),
+    String,
+> {
+    worker.check_inference()?;
+    if interrupt.is_cancelled() {
+        return Err("Completion was cancelled".to_owned());
+    }
+    let wrapped_ctx = worker.ctx.clone();
+    let (load_time, n_threads) = (worker.load_time, worker.n_threads);
+    metrics.queue_depth.inc();
+    let ctx = match interrupt.queue_deadline() {
+        Some(deadline) => timeout_at(deadline.into(), wrapped_ctx.lock_owned())
+            .await
+            .ok(),
+        None => Some(wrapped_ctx.lock_owned().await),
+    };
+    metrics.queue_depth.dec();
+    let ctx = match ctx {
+        Some(ctx) => ctx,
+        // timed out while queued, so there is no partial output
+        None => {
+            let (_, recv) = mpsc::unbounded_channel::<String>();
+            let handle = rocket::tokio::spawn(async {
+                Ok(Completion {
+                    text: String::new(),
+                    tokens: Vec::new(),
+                    n_past: 0,
+                    seed: None,
+                    finish_reason: FinishReason::TIMEOUT,
+                    stop_word: None,
+                    choices: Vec::new(),
+                    timings: Timings::default(),
+                })
+            });
+            return Ok((recv, handle));
+        }
+    };
+    // cancelled while queued
+    if interrupt.is_cancelled() {
+        return Err("Completion was cancelled".to_owned());
+    }
+    // tokenized before generating, so that text that cannot be tokenized is rejected like other bad requests
+    let prompt_tokens = prompt(ctx.ctx.as_ref().ok_or("No model loaded")?)?;
+    let (sender, recv) = mpsc::unbounded_channel::<String>();
+    let handle = spawn_blocking(move || {
+        let mut ctx = ctx;
+        let ModelContext {
+            ctx,
+            kv_tokens,
+            draft,
+            guidance,
+        } = &mut *ctx;
+        let ctx = ctx.as_mut().ok_or("No model loaded")?;
+        if let Some(session) = &session {
+            restore_session(ctx, kv_tokens, session, &prompt_tokens);
+        }
+        let mut completion = generate(
+            ctx,
+            kv_tokens,
+            draft.as_mut(),
+            guidance.as_mut(),
+            prompt_tokens,
+            &params,
+            n_threads,
+            &interrupt,
+            |piece| {
+                sender.send(piece.to_owned()).ok();
+            },
+        )?;
+        if let Some(session) = &session {
+            if save_session(ctx, &session.path, kv_tokens).is_err() {
+                warn!("Unable to save session file {}", session.path.display());
+                std::fs::remove_file(&session.path).ok();
+            }
+        }
+        completion.timings.load_ms = load_time.as_secs_f64() * 1000.0;
+        Ok(completion)
+    });
+    Ok((recv, handle))
+}
+
+// log_fields identify the request in the completion event
+async fn finish_completion(
+    metrics: &Metrics,
+    rate_limiter: &RateLimiter,
+    client: &Client,
+    start: Instant,
+    mut log_fields: Value,
+    handle: JoinHandle<Result<Completion, String>>,
+) -> Result<Completion, String> {
+    let result = match handle.await {
+        Ok(result) => result,
+        Err(_) => Err("Unable to complete: Thread panicked".to_owned()),
+    };
+    log_fields["duration_ms"] = json!(start.elapsed().as_secs_f64() * 1000.0);
+    let completion = match result {
+        Ok(completion) => completion,
+        Err(e) => {
+            log_fields["error"] = json!(e);
+            event(Level::Error, "completion_failed", log_fields);
+            return Err(e);
+        }
+    };
+    log_fields["finish_reason"] = json!(completion.finish_reason);
+    log_fields["seed"] = json!(completion.seed);
+    log_fields["timings"] = json!(completion.timings);
+    event(Level::Info, "completion", log_fields);
+    metrics.observe_completion(&completion.timings);
+    rate_limiter
+        .record_tokens(client, completion.timings.generated_tokens as u64)
+        .await;
+    Ok(completion)
+}
+
+fn error_response((status, message): (Status, String)) -> status::Custom<Json<ModelEventResponse>> {
+    status::Custom(
+        status,
+        Json(ModelEventResponse::ERROR {
+            message: Some(message),
+        }),
+    )
+}
+
+fn require_inference(client: &Client) -> Result<(), (Status, String)> {
+    if !client.api_key.allows(Scope::INFERENCE) {
+        return Err((
+            Status::Forbidden,
+            "API key is not permitted to perform this operation".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+// the request id doubles as the job id, so clients can cancel by the X-Request-Id they sent
+fn start_job(
+    jobs: &Jobs,
+    request_id: &str,
+    client: &Client,
+) -> Result<JobHandle, (Status, String)> {
+    jobs.start(request_id, client.api_key.key.clone()).ok_or((
+        Status::Conflict,
+        "A completion with this request id is already running".to_owned(),
+    ))
+}
+
+// the worker for the model asked for, loading it first if the key may switch models
+// also returns the time spent loading, and applies keep_alive to the model afterwards
+#[allow(clippy::too_many_arguments)]
+async fn ensure_model(
+    state: &RwLock<MainState>,
+    model_dir: &ModelDirectory,
+    metrics: &Metrics,
+    request_id: &str,
+    client: &Client,
+    name: &str,
+    params: ModelParams,
+    keep_alive: Option<&KeepAlive>,
+) -> Result<(Worker, Duration), (Status, String)> {
+    let keep_alive = keep_alive
+        .map(KeepAlive::duration)
+        .transpose()
+        .map_err(|e| (Status::BadRequest, e))?;
+    // the worker is taken under the same lock as the check, so a concurrent load cannot swap the model in between
+    let current = {
+        let state = state.read().await;
+        let loaded = state.current_model.as_deref() == Some(name)
+            && (!params.embedding.unwrap_or(false)
+                || state
+                    .loaded_model
+                    .as_ref()
+                    .is_some_and(|loaded_model| loaded_model.params.embedding));
+        Worker::of(&state).ok().filter(|_| loaded)
+    };
+    let loaded = current.is_some();
+    let mut load_time = Duration::ZERO;
+    let worker = match current {
+        Some(worker) => worker,
+        None => {
+            if !client.api_key.allows(Scope::ADMIN) {
+                return Err((
+                    Status::Forbidden,
+                    "API key is not permitted to load models".to_owned(),
+                ));
+            }
+            let load_start = Instant::now();
+            let worker = load_by_name(state, model_dir, metrics, request_id, name, params).await?;
+            load_time = load_start.elapsed();
+            worker
+        }
+    };
+    {
+        let mut state = state.write().await;
+        match keep_alive {
+            Some(keep_alive) => state.keep_alive = keep_alive,
+            // ollama unloads models it loaded after five minutes unless an idle timeout is configured
+            None if !loaded => {
+                state.keep_alive = state.keep_alive.or(Some(ollama::DEFAULT_KEEP_ALIVE))
+            }
+            None => {}
+        }
+        state.last_used = Instant::now();
+    }
+    Ok((worker, load_time))
+}
+
+// waits for the context like a completion, then runs f with it on a blocking thread
+async fn with_context<T: Send + 'static>(
+    worker: &Worker,
+    metrics: &Metrics,
+    f: impl FnOnce(&mut ModelContext, i32) -> Result<T, (Status, String)> + Send + 'static,
+) -> Result<T, (Status, String)> {
+    metrics.queue_depth.inc();
+    let ctx = worker.ctx.clone().lock_owned().await;
+    metrics.queue_depth.dec();
+    if ctx.ctx.is_none() {
+        return Err((Status::BadRequest, "No model loaded".to_owned()));
+    }
+    let n_threads = worker.n_threads;
+    spawn_blocking(move || {
+        let mut ctx = ctx;
+        f(&mut ctx, n_threads)
+    })
+    .await
+    .unwrap_or_else(|_| Err((Status::InternalServerError, "Thread panicked".to_owned())))
+}
+
+async fn start_embedding(
+    worker: &Worker,
+    metrics: &Metrics,
+    prompt: String,
+) -> Result<Vec<f32>, (Status, String)> {
+    worker
+        .check_inference()
+        .map_err(|e| (Status::BadRequest, e))?;
+    with_context(worker, metrics, move |ctx, n_threads| {
+        let ModelContext { ctx, kv_tokens, .. } = ctx;
+        let ctx = ctx
+            .as_mut()
+            .ok_or((Status::BadRequest, "No model loaded".to_owned()))?;
+        let tokens = tokenize_text(ctx, &prompt, true).map_err(|e| (Status::BadRequest, e))?;
+        inference::embed(ctx, kv_tokens, &tokens, n_threads)
+            .map_err(|e| (Status::InternalServerError, e))
+    })
+    .await
+}
+
+// loads a model before serving and evaluates a token, so the first request does not wait for
+// the weights to be paged in
+async fn preload_model(
+    state: &RwLock<MainState>,
+    model_dir: &ModelDirectory,
+    metrics: &Metrics,
+    name: &str,
+) -> Result<(), (Status, String)> {
+    let worker = load_by_name(
+        state,
+        model_dir,
+        metrics,
+        "preload",
+        name,
+        ModelParams::default(),
+    )
+    .await?;
+    if worker.vocab_only {
+        return Ok(());
+    }
+    let warmup_start = Instant::now();
+    with_context(&worker, metrics, |ctx, n_threads| {
+        let ModelContext {
+            ctx,
+            kv_tokens,
+            draft,
+            ..
+        } = ctx;
+        let ctx = ctx
+            .as_mut()
+            .ok_or((Status::BadRequest, "No model loaded".to_owned()))?;
+        // the beginning of sentence token stays in the KV cache, where prompts reuse it
+        let bos = [unsafe { llama_token_bos() }];
+        inference::catch_up(ctx, kv_tokens, &bos, n_threads)
+            .map_err(|e| (Status::InternalServerError, e))?;
+        if let Some(draft) = draft {
+            inference::catch_up(&mut draft.ctx, &mut draft.kv_tokens, &bos, n_threads)
+                .map_err(|e| (Status::InternalServerError, e))?;
+        }
+        Ok(())
+    })
+    .await?;
+    event(
+        Level::Info,
+        "model_warmup",
+        json!({
+            "model": name,
+            "duration_ms": warmup_start.elapsed().as_secs_f64() * 1000.0,
+        }),
+    );
+    Ok(())
+}
+
+// unloads the model once it has been idle for longer than its keep_alive
+async fn unload_idle_models(state: Arc<RwLock<MainState>>, metrics: Metrics) {
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let idle = {
+            let mut state = state.write().await;
+            // workers and queued completions hold a reference while the context is in use
+            let in_use = state
+                .ctx
+                .as_ref()
+                .map(|wrapped_ctx| Arc::strong_count(wrapped_ctx) > 1);
+            match in_use {
+                Some(true) => {
+                    state.last_used = Instant::now();
+                    false
+                }
+                Some(false) => state
+                    .keep_alive
+                    .is_some_and(|keep_alive| state.last_used.elapsed() >= keep_alive),
+                None => false,
+            }
+        };
+        if idle {
+            unload_model(&state, &metrics, json!({ "reason": "keep_alive" }))
+                .await
+                .ok();
+        }
+    }
+}
+
+// serves the API until the server is shut down
+pub async fn launch(cli: CLI) {
+    logging::init(cli.log_level, cli.log_prompts);
+    let load_params = LoadParams {
+        context_size: cli.ctx_size,
+        gpu_offload: cli.gpu_offload_layers,
+        seed: cli.seed,
+        kv_in_f16: cli.use_f16,
+        no_swap: cli.use_mlock,
+        pin_memory: cli.use_mmap,
+        idle_timeout: cli.idle_timeout.clone().map(KeepAlive::DURATION),
+        path_to_model_dir: cli.model_dir.clone(),
+        n_threads: cli
+            .threads
+            .unwrap_or_else(|| available_parallelism().map(|n| n.get() as i32).unwrap_or(4)),
+    };
+    if let Some(idle_timeout) = &load_params.idle_timeout {
+        idle_timeout.duration().unwrap_or_else(|e| panic!("{}", e));
+    }
+    let model_dir = ModelDirectory::new(load_params.path_to_model_dir.clone());
+    assert!(
+        !model_dir.list().is_empty(),
+        "No models found in {}",
+        &load_params.path_to_model_dir.display()
+    );
+    let api_keys = ApiKeys::load(&cli.api_key, cli.api_keys_file.as_deref())
+        .unwrap_or_else(|e| panic!("{}", e));
+    let rate_limiter = RateLimiter::new(
+        cli.requests_per_minute,
+        cli.token_quota,
+        cli.quota_period,
+        cli.usage_file.clone(),
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+    if api_keys.is_empty() {
+        warn!("No API keys configured, authentication is disabled");
+    }
+    info!("Initializing...");
+    init();
+    info!(
+        "Built with the {} backend: {}",
+        models::BACKEND,
+        unsafe { CStr::from_ptr(llama_print_system_info()) }.to_string_lossy()
+    );
+    let metrics = Metrics::new();
+    let state = Arc::new(RwLock::new(MainState {
+        load_params,
+        ctx: None,
+        current_model: None,
+        loaded_model: None,
+        load_time: None,
+        keep_alive: None,
+        last_used: Instant::now(),
+        loading: Arc::new(Mutex::new(())),
+    }));
+    for name in &cli.preload {
+        if let Err((_, e)) = preload_model(&state, &model_dir, &metrics, name).await {
+            error!("Unable to preload {}: {}", name, e);
+        }
+    }
+    rocket::tokio::spawn(unload_idle_models(state.clone(), metrics.clone()));
+    let launched = rocket::build()
+        .mount(
+            "/api/v1/",
+            rocket::routes![
+                routes::models::change_model,
+                routes::completions::completion,
+                routes::completions::cancel_job,
+                routes::batch::submit_batch,
+                routes::batch::batch_results,
+                routes::completions::chat_session,
+                routes::conversations::create_conversation,
+                routes::conversations::list_conversations,
+                routes::conversations::get_conversation,
+                routes::conversations::delete_conversation,
+                routes::conversations::append_messages,
+                routes::conversations::generate_turn
+            ],
+        )
+        .mount(
+            "/api",
+            rocket::routes![
+                routes::ollama::ollama_generate,
+                routes::ollama::ollama_chat,
+                routes::ollama::ollama_tags,
+                routes::ollama::ollama_show,
+                routes::ollama::ollama_embeddings
+            ],
+        )
+        .mount(
+            "/",
+            rocket::routes![
+                routes::metrics::export_metrics,
+                routes::llamacpp::server_completion,
+                routes::llamacpp::server_tokenize,
+                routes::llamacpp::server_detokenize,
+                routes::llamacpp::server_embedding
+            ],
+        )
+        .attach(RequestCounter)
+        .attach(RequestLogger)
+        .register("/", auth::catchers())
+        .register("/", ratelimit::catchers())
+        .manage(api_keys)
+        .manage(rate_limiter)
+        .manage(metrics.clone())
+        .manage(model_dir)
+        .manage(Jobs::default())
+        .manage(Batches::default())
+        .manage(Conversations::new(cli.data_dir.clone()))
+        .manage(Limits {
+            max_prompt_tokens: cli.max_prompt_tokens,
+            max_tokens: cli.max_tokens,
+            timeout: cli.timeout,
+            first_token_timeout: cli.first_token_timeout,
+            max_n: Some(cli.max_n),
+            max_best_of: Some(cli.max_best_of),
+            max_beam_width: Some(cli.max_beam_width),
+            max_batch_size: Some(cli.max_batch_size),
+        })
+        .manage(state.clone())
+        .launch()
+        .await;
+    if let Err(e) = launched {
+        error!("Unable to launch the server: {}", e);
+    }
+}
@@ -0,0 +1,351 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use rocket::{
+    http::Status,
+    response::status,
+    serde::json::{from_value, json, serde_json::Map, to_string, Json, Value},
+};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    api::conversations::{now, Message, Role},
+    config::ModelParams,
+    engine::{
+        inference::{Completion, FinishReason, GenerationParams},
+        models::{KeepAlive, ModelInfo},
+    },
+    sys::llama_token,
+};
+
+// how long a model loaded by a request stays loaded when the request does not say
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5 * 60);
+
+fn default_stream() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct GenerateRequest {
+    pub model: String,
+    // an empty prompt only loads the model
+    #[serde(default)]
+    pub prompt: String,
+    pub system: Option<String>,
+    // tokens returned by an earlier response, the prompt continues from them
+    #[serde(default)]
+    pub context: Vec<llama_token>,
+    // leaves out the system prompt
+    #[serde(default)]
+    pub raw: bool,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: Map<String, Value>,
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    pub model: String,
+    // no messages only loads the model
+    #[serde(default)]
+    pub messages: Vec<Message>,
+    #[serde(default = "default_stream")]
+    pub stream: bool,
+    #[serde(default)]
+    pub options: Map<String, Value>,
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Deserialize)]
+pub struct EmbeddingsRequest {
+    pub model: String,
+    pub prompt: String,
+    #[serde(default)]
+    pub options: Map<String, Value>,
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Deserialize)]
+pub struct ShowRequest {
+    #[serde(alias = "model")]
+    pub name: String,
+}
+
+// clients add the default tag to names without one
+pub fn model_name(name: &str) -> &str {
+    name.strip_suffix(":latest").unwrap_or(name)
+}
+
+// options that take effect when the model is loaded, the others are ignored until it is reloaded
+pub fn model_params(options: &Map<String, Value>) -> ModelParams {
+    let int = |name| options.get(name).and_then(Value::as_i64).map(|n| n as i32);
+    let flag = |name| options.get(name).and_then(Value::as_bool);
+    ModelParams {
+        n_ctx: int("num_ctx"),
+        n_gpu_layers: int("num_gpu"),
+        use_mmap: flag("use_mmap"),
+        use_mlock: flag("use_mlock"),
+        ..ModelParams::default()
+    }
+}
+
+// sampling options share their names with GenerationParams apart from num_predict
+pub fn generation_params(options: &Map<String, Value>) -> Result<GenerationParams, String> {
+    let mut params = options.clone();
+    if let Some(num_predict) = params.remove("num_predict") {
+        // negative generates until the context is full
+        let max_tokens = match num_predict.as_i64() {
+            Some(n) if n < 0 => json!(usize::MAX),
+            _ => num_predict,
+        };
+        params.insert("max_tokens".to_owned(), max_tokens);
+    }
+    from_value(Value::Object(params)).map_err(|e| format!("Invalid options: {}", e))
+}
+
+fn done_reason(finish_reason: FinishReason) -> &'static str {
+    match finish_reason {
+        FinishReason::STOP => "stop",
+        FinishReason::LENGTH => "length",
+        FinishReason::CANCELLED => "cancelled",
+        FinishReason::TIMEOUT => "timeout",
+    }
+}
+
+fn nanoseconds(ms: f64) -> u64 {
+    (ms * 1_000_000.0) as u64
+}
+
+// sent with the last chunk, durations in nanoseconds
+#[derive(Serialize)]
+pub struct Stats {
+    total_duration: u64,
+    load_duration: u64,
+    prompt_eval_count: usize,
+    prompt_eval_duration: u64,
+    eval_count: usize,
+    eval_duration: u64,
+}
+
+impl Stats {
+    // load is the time this request spent loading the model
+    fn new(completion: &Completion, total: Duration, load: Duration) -> Stats {
+        let timings = &completion.timings;
+        Stats {
+            total_duration: total.as_nanos() as u64,
+            load_duration: load.as_nanos() as u64,
+            prompt_eval_count: timings.prompt_tokens,
+            prompt_eval_duration: nanoseconds(timings.prompt_eval_ms),
+            eval_count: timings.generated_tokens,
+            eval_duration: nanoseconds(timings.eval_ms),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GenerateResponse {
+    model: String,
+    created_at: String,
+    response: String,
+    done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    done_reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    context: Vec<llama_token>,
+    #[serde(flatten)]
+    stats: Option<Stats>,
+}
+
+impl GenerateResponse {
+    pub fn token(model: &str, response: String) -> GenerateResponse {
+        GenerateResponse {
+            model: model.to_owned(),
+            created_at: now(),
+            response,
+            done: false,
+            done_reason: None,
+            context: Vec::new(),
+            stats: None,
+        }
+    }
+
+    pub fn loaded(model: &str) -> GenerateResponse {
+        GenerateResponse {
+            done: true,
+            done_reason: Some("load"),
+            ..GenerateResponse::token(model, String::new())
+        }
+    }
+
+    // response is empty when the text was streamed
+    pub fn done(
+        model: &str,
+        response: String,
+        completion: Completion,
+        total: Duration,
+        load: Duration,
+    ) -> GenerateResponse {
+        GenerateResponse {
+            done: true,
+            done_reason: Some(done_reason(completion.finish_reason)),
+            stats: Some(Stats::new(&completion, total, load)),
+            context: completion.tokens,
+            ..GenerateResponse::token(model, response)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ChatMessage {
+    role: Role,
+    content: String,
+}
+
+#[derive(Serialize)]
+pub struct ChatResponse {
+    model: String,
+    created_at: String,
+    message: ChatMessage,
+    done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    done_reason: Option<&'static str>,
+    #[serde(flatten)]
+    stats: Option<Stats>,
+}
+
+impl ChatResponse {
+    pub fn token(model: &str, content: String) -> ChatResponse {
+        ChatResponse {
+            model: model.to_owned(),
+            created_at: now(),
+            message: ChatMessage {
+                role: Role::ASSISTANT,
+                content,
+            },
+            done: false,
+            done_reason: None,
+            stats: None,
+        }
+    }
+
+    pub fn loaded(model: &str) -> ChatResponse {
+        ChatResponse {
+            done: true,
+            done_reason: Some("load"),
+            ..ChatResponse::token(model, String::new())
+        }
+    }
+
+    // content is empty when the text was streamed
+    pub fn done(
+        model: &str,
+        content: String,
+        completion: &Completion,
+        total: Duration,
+        load: Duration,
+    ) -> ChatResponse {
+        ChatResponse {
+            done: true,
+            done_reason: Some(done_reason(completion.finish_reason)),
+            stats: Some(Stats::new(completion, total, load)),
+            ..ChatResponse::token(model, content)
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EmbeddingsResponse {
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+pub struct ModelDetails {
+    format: &'static str,
+    family: &'static str,
+    parameter_size: String,
+    quantization_level: String,
+}
+
+impl From<&ModelInfo> for ModelDetails {
+    fn from(info: &ModelInfo) -> Self {
+        let n_params = info.n_params();
+        ModelDetails {
+            format: info.format,
+            family: "llama",
+            parameter_size: if n_params >= 1_000_000_000 {
+                format!("{:.1}B", n_params as f64 / 1e9)
+            } else {
+                format!("{}M", n_params / 1_000_000)
+            },
+            // e.g. Q4_0 for mostly_q4_0
+            quantization_level: info
+                .ftype
+                .trim_start_matches("mostly_")
+                .trim_start_matches("all_")
+                .to_uppercase(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TagsModel {
+    name: String,
+    model: String,
+    modified_at: String,
+    size: u64,
+    details: ModelDetails,
+}
+
+impl TagsModel {
+    pub fn new(info: &ModelInfo, modified: SystemTime) -> TagsModel {
+        TagsModel {
+            name: info.name.clone(),
+            model: info.name.clone(),
+            modified_at: DateTime::<Utc>::from(modified)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+            size: info.size,
+            details: ModelDetails::from(info),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Tags {
+    pub models: Vec<TagsModel>,
+}
+
+#[derive(Serialize)]
+pub struct ShowResponse {
+    details: ModelDetails,
+    model_info: Value,
+}
+
+impl From<&ModelInfo> for ShowResponse {
+    fn from(info: &ModelInfo) -> Self {
+        ShowResponse {
+            details: ModelDetails::from(info),
+            model_info: json!({
+                "general.architecture": "llama",
+                "general.parameter_count": info.n_params(),
+                "general.file_type": info.ftype,
+                "llama.vocab_size": info.n_vocab,
+                "llama.embedding_length": info.n_embd,
+                "llama.block_count": info.n_layer,
+                "llama.attention.head_count": info.n_head,
+            }),
+        }
+    }
+}
+
+// one line of a streamed response
+pub fn line(chunk: &impl Serialize) -> String {
+    let mut line = to_string(chunk).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+// ollama clients read the message from an error field
+pub fn error((status, message): (Status, String)) -> status::Custom<Json<Value>> {
+    status::Custom(status, Json(json!({ "error": message })))
+}
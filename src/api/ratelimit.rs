@@ -0,0 +1,299 @@
+use log::error;
+use rocket::{
+    http::{Header, Status},
+    request::{FromRequest, Outcome, Request},
+    serde::json::Json,
+    tokio::{
+        fs::{rename, write},
+        sync::Mutex as AsyncMutex,
+    },
+    Catcher,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    api::{auth::ApiKey, ModelEventResponse},
+    config::QuotaPeriod,
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// generated tokens per identity for the current quota period, persisted to the usage file
+#[derive(Default, Serialize, Deserialize)]
+struct Usage {
+    period: String,
+    tokens: HashMap<String, u64>,
+}
+
+// clones share the same counters
+#[derive(Clone)]
+pub struct RateLimiter {
+    requests_per_minute: Option<u32>,
+    token_quota: Option<u64>,
+    quota_period: QuotaPeriod,
+    usage_file: Option<PathBuf>,
+    windows: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+    usage: Arc<Mutex<Usage>>,
+    // held while the usage file is written
+    writing: Arc<AsyncMutex<()>>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        requests_per_minute: Option<u32>,
+        token_quota: Option<u64>,
+        quota_period: QuotaPeriod,
+        usage_file: Option<PathBuf>,
+    ) -> Result<RateLimiter, String> {
+        let mut usage = Usage::default();
+        if let Some(path) = &usage_file {
+            if path.exists() {
+                let contents = read_to_string(path)
+                    .map_err(|e| format!("Unable to read usage file {}: {}", path.display(), e))?;
+                usage = rocket::serde::json::from_str(&contents)
+                    .map_err(|e| format!("Invalid usage file {}: {}", path.display(), e))?;
+            }
+        }
+        if usage.period != quota_period.current() {
+            usage = Usage {
+                period: quota_period.current(),
+                tokens: HashMap::new(),
+            };
+        }
+        Ok(RateLimiter {
+            requests_per_minute,
+            token_quota,
+            quota_period,
+            usage_file,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+            usage: Arc::new(Mutex::new(usage)),
+            writing: Arc::new(AsyncMutex::new(())),
+        })
+    }
+
+    // returns the seconds until the quota period ends if any identity has used up its quota
+    pub fn check_quota(&self, client: &Client) -> Result<(), u64> {
+        let mut usage = self.usage.lock().unwrap();
+        if usage.period != self.quota_period.current() {
+            usage.period = self.quota_period.current();
+            usage.tokens.clear();
+        }
+        for identity in &client.identities {
+            if let Some(quota) = self.token_quota_for(client, identity) {
+                if usage.tokens.get(identity).copied().unwrap_or(0) >= quota {
+                    return Err(self.quota_period.seconds_remaining());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // counts the request against every identity, returns the seconds to wait if any is over its limit
+    pub fn check(&self, client: &Client) -> Result<(), u64> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: &Client, now: Instant) -> Result<(), u64> {
+        self.check_quota(client)?;
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+        for identity in &client.identities {
+            if let (Some(limit), Some((start, count))) = (
+                self.requests_per_minute_for(client, identity),
+                windows.get(identity),
+            ) {
+                if *count >= limit {
+                    return Err((WINDOW - now.duration_since(*start)).as_secs().max(1));
+                }
+            }
+        }
+        for identity in &client.identities {
+            windows.entry(identity.clone()).or_insert((now, 0)).1 += 1;
+        }
+        Ok(())
+    }
+
+    fn requests_per_minute_for(&self, client: &Client, identity: &str) -> Option<u32> {
+        if identity.starts_with("key:") {
+            client
+                .api_key
+                .requests_per_minute
+                .or(self.requests_per_minute)
+        } else {
+            self.requests_per_minute
+        }
+    }
+
+    fn token_quota_for(&self, client: &Client, identity: &str) -> Option<u64> {
+        if identity.starts_with("key:") {
+            client.api_key.token_quota.or(self.token_quota)
+        } else {
+            self.token_quota
+        }
+    }
+
+    // charges generated tokens to the client and persists the counters
+    pub async fn record_tokens(&self, client: &Client, tokens: u64) {
+        {
+            let mut usage = self.usage.lock().unwrap();
+            if usage.period != self.quota_period.current() {
+                usage.period = self.quota_period.current();
+                usage.tokens.clear();
+            }
+            for identity in &client.identities {
+                *usage.tokens.entry(identity.clone()).or_insert(0) += tokens;
+            }
+        }
+        if let Some(path) = &self.usage_file {
+            // the counters are serialised once the lock is held, so a later write never has older counters
+            let _writing = self.writing.lock().await;
+            let contents = rocket::serde::json::to_string(&*self.usage.lock().unwrap());
+            if let Ok(contents) = contents {
+                // renamed into place so that a crash never leaves a partly written file
+                let mut temp = path.clone().into_os_string();
+                temp.push(".tmp");
+                let written = match write(&temp, contents).await {
+                    Ok(()) => rename(&temp, path).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = written {
+                    error!("Unable to write usage file: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// request guard that authenticates the caller and enforces the rate limits for its key and address
+#[derive(Clone)]
+pub struct Client {
+    pub api_key: ApiKey,
+    identities: Vec<String>,
+}
+
+struct RetryAfter(u64);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Client {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let api_key = match request.guard::<ApiKey>().await {
+            Outcome::Success(api_key) => api_key,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        let mut identities = Vec::new();
+        // the usage file holds identities, so keys are stored by their id
+        if let Some(id) = api_key.id() {
+            identities.push(format!("key:{}", id));
+        }
+        if let Some(ip) = request.client_ip() {
+            identities.push(format!("ip:{}", ip));
+        }
+        let client = Client {
+            api_key,
+            identities,
+        };
+        match request.rocket().state::<RateLimiter>() {
+            Some(limiter) => match limiter.check(&client) {
+                Ok(()) => Outcome::Success(client),
+                Err(retry_after) => {
+                    request.local_cache(|| RetryAfter(retry_after));
+                    Outcome::Error((Status::TooManyRequests, ()))
+                }
+            },
+            None => Outcome::Success(client),
+        }
+    }
+}
+
+#[derive(rocket::Responder)]
+#[response(status = 429)]
+struct TooManyRequests {
+    inner: Json<ModelEventResponse>,
+    retry_after: Header<'static>,
+}
+
+#[rocket::catch(429)]
+fn too_many_requests(request: &Request) -> TooManyRequests {
+    let retry_after = request.local_cache(|| RetryAfter(WINDOW.as_secs())).0;
+    TooManyRequests {
+        inner: Json(ModelEventResponse::ERROR {
+            message: Some("Rate limit exceeded".to_owned()),
+        }),
+        retry_after: Header::new("Retry-After", retry_after.to_string()),
+    }
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    rocket::catchers![too_many_requests]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> Client {
+        Client {
+            api_key: ApiKey::anonymous(),
+            identities: vec!["ip:127.0.0.1".to_owned()],
+        }
+    }
+
+    #[test]
+    fn window_rolls_over() {
+        let limiter = RateLimiter::new(Some(2), None, QuotaPeriod::DAY, None).unwrap();
+        let client = client();
+        let start = Instant::now();
+        assert!(limiter.check_at(&client, start).is_ok());
+        assert!(limiter.check_at(&client, start).is_ok());
+        assert_eq!(
+            limiter.check_at(&client, start + Duration::from_secs(15)),
+            Err(45)
+        );
+        assert_eq!(
+            limiter.check_at(&client, start + Duration::from_secs(59)),
+            Err(1)
+        );
+        assert!(limiter.check_at(&client, start + WINDOW).is_ok());
+        assert!(limiter.check_at(&client, start + WINDOW).is_ok());
+        assert!(limiter.check_at(&client, start + WINDOW).is_err());
+    }
+
+    #[test]
+    fn windows_are_per_identity() {
+        let limiter = RateLimiter::new(Some(1), None, QuotaPeriod::DAY, None).unwrap();
+        let start = Instant::now();
+        assert!(limiter.check_at(&client(), start).is_ok());
+        assert!(limiter.check_at(&client(), start).is_err());
+        let other = Client {
+            api_key: ApiKey::anonymous(),
+            identities: vec!["ip:10.0.0.1".to_owned()],
+        };
+        assert!(limiter.check_at(&other, start).is_ok());
+    }
+
+    #[test]
+    fn quota_resets_with_the_period() {
+        let limiter = RateLimiter::new(None, Some(10), QuotaPeriod::DAY, None).unwrap();
+        let client = client();
+        limiter
+            .usage
+            .lock()
+            .unwrap()
+            .tokens
+            .insert("ip:127.0.0.1".to_owned(), 10);
+        assert!(limiter.check_quota(&client).is_err());
+        limiter.usage.lock().unwrap().period = "2000-01-01".to_owned();
+        assert!(limiter.check_quota(&client).is_ok());
+        assert!(limiter.usage.lock().unwrap().tokens.is_empty());
+    }
+}
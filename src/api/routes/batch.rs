@@ -0,0 +1,237 @@
+use rocket::{
+    http::Status,
+    response::status,
+    serde::json::{from_value, json, serde_json::Map, Json, Value},
+    tokio::sync::RwLock,
+};
+use serde::Deserialize;
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    time::Instant,
+};
+
+use crate::{
+    api::{
+        batch::{BatchProgress, BatchStatus, Batches},
+        error_response, finish_completion,
+        jobs::Jobs,
+        logging::{self, RequestId},
+        metrics::Metrics,
+        ratelimit::{Client, RateLimiter},
+        require_inference, set_keep_alive, start_completion, start_job, CompletionResponse,
+        MainState, ModelEventResponse, Worker,
+    },
+    engine::{
+        inference::{GenerationParams, Interrupt, Limits},
+        tokenize_text, LlamaContext,
+    },
+};
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum BatchItem {
+    PROMPT(String),
+    ITEM {
+        prompt: String,
+        #[serde(flatten)]
+        params: Map<String, Value>,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    prompts: Vec<BatchItem>,
+    // returns a batch id straight away, results are fetched from /batch/<id>
+    #[serde(default, rename = "async")]
+    run_async: bool,
+    // shared by every prompt, parameters given with a prompt take precedence
+    #[serde(flatten)]
+    params: Map<String, Value>,
+}
+
+// runs the prompts one after another, each queueing for the context like a single completion
+#[allow(clippy::too_many_arguments)]
+async fn run_batch(
+    worker: &Worker,
+    metrics: &Metrics,
+    rate_limiter: &RateLimiter,
+    client: &Client,
+    log_fields: &Value,
+    items: Vec<(String, GenerationParams)>,
+    cancelled: Arc<AtomicBool>,
+    mut on_result: impl FnMut(Value),
+) {
+    for (index, (prompt, params)) in items.into_iter().enumerate() {
+        let start = Instant::now();
+        let mut log_fields = log_fields.clone();
+        log_fields["index"] = json!(index);
+        if logging::log_prompts() {
+            log_fields["prompt"] = json!(prompt);
+        }
+        let prompt = move |ctx: &LlamaContext| tokenize_text(ctx, &prompt, true);
+        // tokens generated by earlier prompts count against the quota
+        let interrupt = rate_limiter
+            .check_quota(client)
+            .map_err(|_| "Token quota exceeded".to_owned())
+            .and_then(|()| Interrupt::new(cancelled.clone(), start, &params));
+        let started = match interrupt {
+            Ok(interrupt) => {
+                start_completion(worker, metrics, prompt, params, interrupt, None).await
+            }
+            Err(e) => Err(e),
+        };
+        let result = match started {
+            Ok((mut recv, handle)) => {
+                while recv.recv().await.is_some() {}
+                finish_completion(metrics, rate_limiter, client, start, log_fields, handle).await
+            }
+            Err(e) => Err(e),
+        };
+        on_result(match result {
+            Ok(completion) => json!(CompletionResponse::from(completion)),
+            Err(e) => json!(ModelEventResponse::ERROR { message: Some(e) }),
+        });
+    }
+}
+
+#[rocket::post("/batch", data = "<user_input>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn submit_batch(
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &rocket::State<Metrics>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    jobs: &rocket::State<Jobs>,
+    batches: &rocket::State<Batches>,
+    limits: &rocket::State<Limits>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<BatchRequest>,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let bad_request = |message: String| {
+        status::Custom(
+            Status::BadRequest,
+            Json(ModelEventResponse::ERROR {
+                message: Some(message),
+            }),
+        )
+    };
+    let request = user_input.0;
+    if request.prompts.is_empty() {
+        return Err(bad_request("No prompts given".to_owned()));
+    }
+    if let Some(max_batch_size) = limits.max_batch_size {
+        if request.prompts.len() > max_batch_size {
+            return Err(bad_request(format!(
+                "Too many prompts, at most {} are allowed",
+                max_batch_size
+            )));
+        }
+    }
+    let mut items = Vec::with_capacity(request.prompts.len());
+    for (index, item) in request.prompts.into_iter().enumerate() {
+        let (prompt, item_params) = match item {
+            BatchItem::PROMPT(prompt) => (prompt, Map::new()),
+            BatchItem::ITEM { prompt, params } => (prompt, params),
+        };
+        let mut params = request.params.clone();
+        params.extend(item_params);
+        let mut params: GenerationParams = from_value(Value::Object(params))
+            .map_err(|e| bad_request(format!("Invalid parameters for prompt {}: {}", index, e)))?;
+        params
+            .clamp(limits)
+            .map_err(|e| bad_request(format!("Invalid parameters for prompt {}: {}", index, e)))?;
+        items.push((prompt, params));
+    }
+    // the last prompt's keep_alive is what applies once the batch is done
+    set_keep_alive(
+        state,
+        items
+            .last()
+            .and_then(|(_, params)| params.keep_alive.as_ref()),
+    )
+    .await
+    .map_err(error_response)?;
+    let worker = Worker::current(state).await.map_err(bad_request)?;
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": state.read().await.current_model,
+    });
+    if request.run_async {
+        let batch_id = batches.create(client.api_key.key.clone(), items.len());
+        // the batch id doubles as the job id, it is fresh so it cannot be taken
+        let job = jobs
+            .start(&batch_id, client.api_key.key.clone())
+            .expect("Batch id is already in use");
+        log_fields["batch"] = json!(batch_id);
+        let metrics = metrics.inner().clone();
+        let rate_limiter = rate_limiter.inner().clone();
+        let batches = batches.inner().clone();
+        let id = batch_id.clone();
+        rocket::tokio::spawn(async move {
+            run_batch(
+                &worker,
+                &metrics,
+                &rate_limiter,
+                &client,
+                &log_fields,
+                items,
+                job.cancelled(),
+                |result| batches.push(&id, result),
+            )
+            .await;
+            batches.finish(&id);
+        });
+        Ok(status::Accepted(Json(ModelEventResponse::OK {
+            message: Some(batch_id),
+        })))
+    } else {
+        let job = start_job(jobs, &request_id.id, &client).map_err(error_response)?;
+        let total = items.len();
+        let mut results = Vec::with_capacity(total);
+        run_batch(
+            &worker,
+            metrics,
+            rate_limiter,
+            &client,
+            &log_fields,
+            items,
+            job.cancelled(),
+            |result| results.push(result),
+        )
+        .await;
+        Ok(status::Accepted(Json(ModelEventResponse::OKBatch(
+            BatchProgress {
+                status: BatchStatus::DONE,
+                total,
+                completed: total,
+                message: results,
+            },
+        ))))
+    }
+}
+
+#[rocket::get("/batch/<id>")]
+pub async fn batch_results(
+    batches: &rocket::State<Batches>,
+    client: Client,
+    id: &str,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    match batches.get(id, &client.api_key) {
+        Ok(progress) => Ok(status::Accepted(Json(ModelEventResponse::OKBatch(
+            progress,
+        )))),
+        Err(status) if status == Status::NotFound => Err(status::Custom(
+            Status::NotFound,
+            Json(ModelEventResponse::ERROR {
+                message: Some("No batch with this id".to_owned()),
+            }),
+        )),
+        Err(status) => Err(status::Custom(
+            status,
+            Json(ModelEventResponse::ERROR {
+                message: Some("API key is not permitted to perform this operation".to_owned()),
+            }),
+        )),
+    }
+}
@@ -0,0 +1,222 @@
+use log::Level;
+use rocket::{
+    http::Status,
+    response::{
+        status,
+        stream::{Event, EventStream},
+    },
+    serde::json::{json, Json},
+    tokio::sync::RwLock,
+    Either,
+};
+use rocket_ws::{Channel, WebSocket};
+use serde::Deserialize;
+use std::{sync::Arc, time::Instant};
+
+use crate::{
+    api::{
+        chat::ChatSession,
+        error_response, finish_completion,
+        jobs::Jobs,
+        logging::{self, event, RequestId},
+        metrics::Metrics,
+        ratelimit::{Client, RateLimiter},
+        require_inference, set_keep_alive, start_completion, start_job, CompletionResponse,
+        MainState, ModelEventResponse, Worker,
+    },
+    engine::{
+        inference::{GenerationParams, Interrupt, Limits},
+        tokenize_text, LlamaContext,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    prompt: String,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    params: GenerationParams,
+}
+
+#[rocket::post("/completions", data = "<user_input>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn completion<'r>(
+    state: &'r rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &'r rocket::State<Metrics>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    jobs: &rocket::State<Jobs>,
+    limits: &rocket::State<Limits>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<CompletionRequest>,
+) -> Result<
+    Either<Json<CompletionResponse>, EventStream![Event + 'r]>,
+    status::Custom<Json<ModelEventResponse>>,
+> {
+    require_inference(&client).map_err(error_response)?;
+    let mut request = user_input.0;
+    request
+        .params
+        .clamp(limits)
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    // choices and beams are ranked at the end, so there is nothing to stream
+    if request.stream && !request.params.streams() {
+        return Err(status::Custom(
+            Status::BadRequest,
+            Json(ModelEventResponse::ERROR {
+                message: Some(
+                    "Streaming is not supported with n, best_of or beam search".to_owned(),
+                ),
+            }),
+        ));
+    }
+    set_keep_alive(state, request.params.keep_alive.as_ref())
+        .await
+        .map_err(error_response)?;
+    let start = Instant::now();
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": state.read().await.current_model,
+        "stream": request.stream,
+    });
+    if logging::log_prompts() {
+        log_fields["prompt"] = json!(request.prompt);
+    }
+    let job = start_job(jobs, &request_id.id, &client).map_err(error_response)?;
+    let interrupt = Interrupt::new(job.cancelled(), start, &request.params)
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    let started = match Worker::current(state).await {
+        Ok(worker) => {
+            let prompt = request.prompt;
+            let prompt = move |ctx: &LlamaContext| tokenize_text(ctx, &prompt, true);
+            start_completion(&worker, metrics, prompt, request.params, interrupt, None).await
+        }
+        Err(e) => Err(e),
+    };
+    let (mut recv, handle) = started.map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            Json(ModelEventResponse::ERROR { message: Some(e) }),
+        )
+    })?;
+    if request.stream {
+        // accounting runs on its own task so a disconnected client is still charged
+        let finish_metrics = metrics.inner().clone();
+        let finish_rate_limiter = rate_limiter.inner().clone();
+        let finished = rocket::tokio::spawn(async move {
+            finish_completion(
+                &finish_metrics,
+                &finish_rate_limiter,
+                &client,
+                start,
+                log_fields,
+                handle,
+            )
+            .await
+        });
+        Ok(Either::Right(EventStream! {
+            // dropped with the stream when the client disconnects, which cancels the generation
+            let _job = job;
+            let mut first_token = true;
+            while let Some(piece) = recv.recv().await {
+                if first_token {
+                    metrics.observe_first_token(start.elapsed());
+                    first_token = false;
+                }
+                yield Event::json(&CompletionResponse::TOKEN { message: piece });
+            }
+            match finished.await {
+                Ok(Ok(completion)) => yield Event::json(&CompletionResponse::from(completion)),
+                Ok(Err(e)) => yield Event::json(&ModelEventResponse::ERROR { message: Some(e) }),
+                Err(_) => yield Event::json(&ModelEventResponse::ERROR {
+                    message: Some("Unable to complete: Thread panicked".to_owned()),
+                }),
+            }
+        }))
+    } else {
+        if recv.recv().await.is_some() {
+            metrics.observe_first_token(start.elapsed());
+        }
+        while recv.recv().await.is_some() {}
+        finish_completion(metrics, rate_limiter, &client, start, log_fields, handle)
+            .await
+            .map(|completion| Either::Left(Json(completion.into())))
+            .map_err(|e| {
+                status::Custom(
+                    Status::InternalServerError,
+                    Json(ModelEventResponse::ERROR { message: Some(e) }),
+                )
+            })
+    }
+}
+
+#[rocket::delete("/jobs/<id>")]
+pub async fn cancel_job(
+    jobs: &rocket::State<Jobs>,
+    request_id: &RequestId,
+    client: Client,
+    id: &str,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    match jobs.cancel(id, &client.api_key) {
+        Ok(()) => {
+            event(
+                Level::Info,
+                "completion_cancel",
+                json!({ "request_id": request_id.id, "job": id }),
+            );
+            Ok(status::Accepted(Json(ModelEventResponse::OK {
+                message: None,
+            })))
+        }
+        Err(status) if status == Status::NotFound => Err(status::Custom(
+            Status::NotFound,
+            Json(ModelEventResponse::ERROR {
+                message: Some("No running completion with this id".to_owned()),
+            }),
+        )),
+        Err(status) => Err(status::Custom(
+            status,
+            Json(ModelEventResponse::ERROR {
+                message: Some("API key is not permitted to perform this operation".to_owned()),
+            }),
+        )),
+    }
+}
+
+// interactive chat, see ChatSession for the protocol
+#[rocket::get("/chat")]
+#[allow(clippy::too_many_arguments)]
+pub async fn chat_session(
+    ws: WebSocket,
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &rocket::State<Metrics>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    jobs: &rocket::State<Jobs>,
+    limits: &rocket::State<Limits>,
+    request_id: &RequestId,
+    client: Client,
+) -> Result<Channel<'static>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    // turns take the worker of whichever model is loaded at the time, but one has to be loaded to start
+    Worker::current(state).await.map_err(|e| {
+        status::Custom(
+            Status::BadRequest,
+            Json(ModelEventResponse::ERROR { message: Some(e) }),
+        )
+    })?;
+    let session = ChatSession {
+        state: state.inner().clone(),
+        metrics: metrics.inner().clone(),
+        rate_limiter: rate_limiter.inner().clone(),
+        client,
+        limits: limits.inner().clone(),
+        jobs: jobs.inner().clone(),
+        log_fields: json!({
+            "request_id": request_id.id,
+            "chat": true,
+        }),
+        request_id: request_id.id.clone(),
+    };
+    Ok(ws.channel(move |stream| Box::pin(session.run(stream))))
+}
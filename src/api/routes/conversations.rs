@@ -0,0 +1,298 @@
+use rocket::{
+    http::Status,
+    response::{
+        status,
+        stream::{Event, EventStream},
+    },
+    serde::json::{json, Json, Value},
+    tokio::sync::RwLock,
+    Either,
+};
+use serde::Deserialize;
+use std::{sync::Arc, time::Instant};
+
+use crate::{
+    api::{
+        conversations::{self, ChatFormat, Conversations, Message, Role},
+        error_response, finish_completion,
+        jobs::Jobs,
+        logging::{self, RequestId},
+        metrics::Metrics,
+        ratelimit::{Client, RateLimiter},
+        require_inference, set_keep_alive, start_completion, start_job, CompletionResponse,
+        MainState, ModelEventResponse, Worker,
+    },
+    engine::{
+        inference::{Completion, GenerationParams, Interrupt, Limits},
+        tokenize_text, LlamaContext, Session,
+    },
+};
+
+#[derive(Deserialize)]
+pub struct ConversationRequest {
+    title: Option<String>,
+    #[serde(default)]
+    metadata: Value,
+    #[serde(default)]
+    format: ChatFormat,
+    #[serde(default)]
+    messages: Vec<Message>,
+}
+
+#[rocket::post("/conversations", data = "<user_input>")]
+pub async fn create_conversation(
+    conversations: &rocket::State<Conversations>,
+    client: Client,
+    user_input: Json<ConversationRequest>,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let request = user_input.0;
+    let conversation = conversations
+        .create(
+            &client.api_key,
+            request.title,
+            request.metadata,
+            request.format,
+            request.messages,
+        )
+        .await
+        .map_err(error_response)?;
+    Ok(status::Accepted(Json(ModelEventResponse::OKConversation {
+        message: conversation,
+    })))
+}
+
+// conversations owned by the key, or every conversation for admin keys
+#[rocket::get("/conversations")]
+pub async fn list_conversations(
+    conversations: &rocket::State<Conversations>,
+    client: Client,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let list = conversations
+        .list(&client.api_key)
+        .await
+        .map_err(error_response)?;
+    Ok(status::Accepted(Json(
+        ModelEventResponse::OKConversations { message: list },
+    )))
+}
+
+#[rocket::get("/conversations/<id>")]
+pub async fn get_conversation(
+    conversations: &rocket::State<Conversations>,
+    client: Client,
+    id: &str,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let conversation = conversations
+        .get(id, &client.api_key)
+        .await
+        .map_err(error_response)?;
+    Ok(status::Accepted(Json(ModelEventResponse::OKConversation {
+        message: conversation,
+    })))
+}
+
+#[rocket::delete("/conversations/<id>")]
+pub async fn delete_conversation(
+    conversations: &rocket::State<Conversations>,
+    client: Client,
+    id: &str,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    conversations
+        .delete(id, &client.api_key)
+        .await
+        .map_err(error_response)?;
+    Ok(status::Accepted(Json(ModelEventResponse::OK {
+        message: None,
+    })))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum NewMessages {
+    MESSAGES { messages: Vec<Message> },
+    MESSAGE(Message),
+}
+
+#[rocket::post("/conversations/<id>/messages", data = "<user_input>")]
+pub async fn append_messages(
+    conversations: &rocket::State<Conversations>,
+    client: Client,
+    id: &str,
+    user_input: Json<NewMessages>,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let messages = match user_input.0 {
+        NewMessages::MESSAGES { messages } => messages,
+        NewMessages::MESSAGE(message) => vec![message],
+    };
+    let conversation = conversations
+        .update(id, &client.api_key, |conversation| {
+            conversation.messages.extend(messages)
+        })
+        .await
+        .map_err(error_response)?;
+    Ok(status::Accepted(Json(ModelEventResponse::OKConversation {
+        message: conversation,
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct GenerateRequest {
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    params: GenerationParams,
+}
+
+// completes the next assistant turn and appends it to the conversation
+// the KV cache is saved to the conversation's session file afterwards, so resuming it
+// after other requests used the context only evaluates what was added since
+#[rocket::post("/conversations/<id>/generate", data = "<user_input>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn generate_turn<'r>(
+    state: &'r rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &'r rocket::State<Metrics>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    jobs: &rocket::State<Jobs>,
+    limits: &rocket::State<Limits>,
+    conversations: &rocket::State<Conversations>,
+    request_id: &RequestId,
+    client: Client,
+    id: &str,
+    user_input: Json<GenerateRequest>,
+) -> Result<
+    Either<Json<CompletionResponse>, EventStream![Event + 'r]>,
+    status::Custom<Json<ModelEventResponse>>,
+> {
+    require_inference(&client).map_err(error_response)?;
+    let conversation = conversations
+        .get(id, &client.api_key)
+        .await
+        .map_err(error_response)?;
+    let mut request = user_input.0;
+    request
+        .params
+        .clamp(limits)
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    request.params.stop.push(conversation.format.stop());
+    if request.stream && !request.params.streams() {
+        return Err(error_response((
+            Status::BadRequest,
+            "Streaming is not supported with n, best_of or beam search".to_owned(),
+        )));
+    }
+    set_keep_alive(state, request.params.keep_alive.as_ref())
+        .await
+        .map_err(error_response)?;
+    let start = Instant::now();
+    let model = state.read().await.current_model.clone();
+    let prompt = conversation.format.render(&conversation.messages);
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": model,
+        "stream": request.stream,
+        "conversation": id,
+    });
+    if logging::log_prompts() {
+        log_fields["prompt"] = json!(prompt);
+    }
+    let job = start_job(jobs, &request_id.id, &client).map_err(error_response)?;
+    let interrupt = Interrupt::new(job.cancelled(), start, &request.params)
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    let worker = Worker::current(state)
+        .await
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    // a session saved by another model or with other context parameters does not fit the context
+    let session_params = worker.session_params.clone();
+    let session = Session {
+        path: conversations.session_path(id).map_err(error_response)?,
+        n_tokens: if session_params.is_some() && conversation.session_params == session_params {
+            conversation.session_tokens
+        } else {
+            0
+        },
+    };
+    let prompt = move |ctx: &LlamaContext| tokenize_text(ctx, &prompt, true);
+    let (mut recv, handle) = start_completion(
+        &worker,
+        metrics,
+        prompt,
+        request.params,
+        interrupt,
+        Some(session),
+    )
+    .await
+    .map_err(|e| error_response((Status::BadRequest, e)))?;
+    // the turn is stored on its own task so it is kept when a streaming client disconnects
+    let finish_metrics = metrics.inner().clone();
+    let finish_rate_limiter = rate_limiter.inner().clone();
+    let conversations = conversations.inner().clone();
+    let id = id.to_owned();
+    let finished = rocket::tokio::spawn(async move {
+        let completion = finish_completion(
+            &finish_metrics,
+            &finish_rate_limiter,
+            &client,
+            start,
+            log_fields,
+            handle,
+        )
+        .await?;
+        // timed out or cancelled before the first token, there is no turn to store
+        if completion.timings.generated_tokens == 0 {
+            return Ok(completion);
+        }
+        let content = completion.text.trim().to_owned();
+        let n_past = completion.n_past;
+        conversations
+            .update(&id, &client.api_key, |conversation| {
+                conversation.messages.push(Message {
+                    role: Role::ASSISTANT,
+                    content,
+                    created: conversations::now(),
+                });
+                conversation.session_params = session_params;
+                conversation.session_tokens = n_past;
+            })
+            .await
+            .map_err(|(_, message)| message)?;
+        Ok::<Completion, String>(completion)
+    });
+    if request.stream {
+        Ok(Either::Right(EventStream! {
+            let _job = job;
+            let mut first_token = true;
+            while let Some(piece) = recv.recv().await {
+                if first_token {
+                    metrics.observe_first_token(start.elapsed());
+                    first_token = false;
+                }
+                yield Event::json(&CompletionResponse::TOKEN { message: piece });
+            }
+            match finished.await {
+                Ok(Ok(completion)) => yield Event::json(&CompletionResponse::from(completion)),
+                Ok(Err(e)) => yield Event::json(&ModelEventResponse::ERROR { message: Some(e) }),
+                Err(_) => yield Event::json(&ModelEventResponse::ERROR {
+                    message: Some("Unable to complete: Thread panicked".to_owned()),
+                }),
+            }
+        }))
+    } else {
+        if recv.recv().await.is_some() {
+            metrics.observe_first_token(start.elapsed());
+        }
+        while recv.recv().await.is_some() {}
+        match finished.await {
+            Ok(Ok(completion)) => Ok(Either::Left(Json(completion.into()))),
+            Ok(Err(e)) => Err(error_response((Status::InternalServerError, e))),
+            Err(_) => Err(error_response((
+                Status::InternalServerError,
+                "Unable to complete: Thread panicked".to_owned(),
+            ))),
+        }
+    }
+}
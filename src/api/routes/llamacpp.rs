@@ -0,0 +1,284 @@
+use log::Level;
+use rocket::{
+    http::Status,
+    response::{
+        status,
+        stream::{Event, EventStream},
+    },
+    serde::json::{json, Json},
+    tokio::sync::RwLock,
+    Either,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use crate::{
+    api::{
+        error_response, finish_completion,
+        jobs::Jobs,
+        llamacpp,
+        logging::{self, event, RequestId},
+        metrics::Metrics,
+        ratelimit::{Client, RateLimiter},
+        require_inference, set_keep_alive, start_completion, start_embedding, start_job,
+        with_context, MainState, ModelEventResponse, Worker,
+    },
+    engine::{
+        inference::{self, Completion, Interrupt, Limits},
+        tokenize_text, LlamaContext,
+    },
+    sys::llama_n_ctx,
+};
+
+// the llama.cpp server example's /completion, streamed as server-sent events when stream is set
+#[rocket::post("/completion", data = "<user_input>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn server_completion<'r>(
+    state: &'r rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &'r rocket::State<Metrics>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    jobs: &rocket::State<Jobs>,
+    limits: &rocket::State<Limits>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<llamacpp::CompletionRequest>,
+) -> Result<
+    Either<Json<llamacpp::CompletionResponse>, EventStream![Event + 'r]>,
+    status::Custom<Json<ModelEventResponse>>,
+> {
+    require_inference(&client).map_err(error_response)?;
+    let request = user_input.0;
+    let mut params = llamacpp::generation_params(&request.options)
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    params
+        .clamp(limits)
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    if request.stream && !params.streams() {
+        return Err(error_response((
+            Status::BadRequest,
+            "Streaming is not supported with n, best_of or beam search".to_owned(),
+        )));
+    }
+    set_keep_alive(state, params.keep_alive.as_ref())
+        .await
+        .map_err(error_response)?;
+    let start = Instant::now();
+    let (model, n_ctx) = {
+        let state = state.read().await;
+        (
+            state.current_model.clone(),
+            state
+                .loaded_model
+                .as_ref()
+                .map(|loaded_model| loaded_model.n_ctx),
+        )
+    };
+    let settings = llamacpp::generation_settings(
+        &params,
+        model.as_deref(),
+        n_ctx,
+        request.n_keep,
+        request.stream,
+    );
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": model,
+        "stream": request.stream,
+        "api": "llama.cpp",
+    });
+    if logging::log_prompts() {
+        log_fields["prompt"] = json!(request.prompt);
+    }
+    let job = start_job(jobs, &request_id.id, &client).map_err(error_response)?;
+    let interrupt = Interrupt::new(job.cancelled(), start, &params)
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    let truncated = Arc::new(AtomicBool::new(false));
+    let prompt = {
+        let text = request.prompt.clone();
+        let truncated = truncated.clone();
+        let n_keep = request.n_keep;
+        move |ctx: &LlamaContext| {
+            let mut tokens = tokenize_text(ctx, &text, true)?;
+            let n_ctx = unsafe { llama_n_ctx(ctx.as_ptr()) } as usize;
+            if llamacpp::truncate_prompt(&mut tokens, n_keep, n_ctx) {
+                truncated.store(true, Ordering::Relaxed);
+            }
+            Ok(tokens)
+        }
+    };
+    let started = match Worker::current(state).await {
+        Ok(worker) => start_completion(&worker, metrics, prompt, params, interrupt, None).await,
+        Err(e) => Err(e),
+    };
+    let (mut recv, handle) = started.map_err(|e| error_response((Status::BadRequest, e)))?;
+    let text = request.prompt;
+    let result = move |completion: &Completion| {
+        llamacpp::CompletionResult::new(
+            completion,
+            model,
+            text,
+            settings,
+            truncated.load(Ordering::Relaxed),
+        )
+    };
+    if request.stream {
+        let finish_metrics = metrics.inner().clone();
+        let finish_rate_limiter = rate_limiter.inner().clone();
+        let finished = rocket::tokio::spawn(async move {
+            finish_completion(
+                &finish_metrics,
+                &finish_rate_limiter,
+                &client,
+                start,
+                log_fields,
+                handle,
+            )
+            .await
+        });
+        Ok(Either::Right(EventStream! {
+            let _job = job;
+            let mut first_token = true;
+            while let Some(piece) = recv.recv().await {
+                if first_token {
+                    metrics.observe_first_token(start.elapsed());
+                    first_token = false;
+                }
+                yield Event::json(&llamacpp::CompletionResponse::token(piece));
+            }
+            match finished.await {
+                Ok(Ok(completion)) => yield Event::json(&llamacpp::CompletionResponse::done(
+                    String::new(),
+                    result(&completion),
+                )),
+                Ok(Err(e)) => yield Event::json(&ModelEventResponse::ERROR { message: Some(e) }),
+                Err(_) => yield Event::json(&ModelEventResponse::ERROR {
+                    message: Some("Unable to complete: Thread panicked".to_owned()),
+                }),
+            }
+        }))
+    } else {
+        if recv.recv().await.is_some() {
+            metrics.observe_first_token(start.elapsed());
+        }
+        while recv.recv().await.is_some() {}
+        finish_completion(metrics, rate_limiter, &client, start, log_fields, handle)
+            .await
+            .map(|completion| {
+                let result = result(&completion);
+                Either::Left(Json(llamacpp::CompletionResponse::done(
+                    completion.text,
+                    result,
+                )))
+            })
+            .map_err(|e| error_response((Status::InternalServerError, e)))
+    }
+}
+
+// tokens of content without the beginning of sentence token, as the server example returns them
+#[rocket::post("/tokenize", data = "<user_input>")]
+pub async fn server_tokenize(
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &rocket::State<Metrics>,
+    client: Client,
+    user_input: Json<llamacpp::TokenizeRequest>,
+) -> Result<Json<llamacpp::TokenizeResponse>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let worker = Worker::current(state)
+        .await
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    let content = user_input.0.content;
+    with_context(&worker, metrics, move |ctx, _| {
+        let ctx = ctx
+            .ctx
+            .as_ref()
+            .ok_or((Status::BadRequest, "No model loaded".to_owned()))?;
+        tokenize_text(ctx, &content, false).map_err(|e| (Status::BadRequest, e))
+    })
+    .await
+    .map(|tokens| Json(llamacpp::TokenizeResponse { tokens }))
+    .map_err(error_response)
+}
+
+#[rocket::post("/detokenize", data = "<user_input>")]
+pub async fn server_detokenize(
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &rocket::State<Metrics>,
+    client: Client,
+    user_input: Json<llamacpp::DetokenizeRequest>,
+) -> Result<Json<llamacpp::DetokenizeResponse>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let worker = Worker::current(state)
+        .await
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    let tokens = user_input.0.tokens;
+    worker.check_tokens(&tokens).map_err(error_response)?;
+    with_context(&worker, metrics, move |ctx, _| {
+        let ctx = ctx
+            .ctx
+            .as_ref()
+            .ok_or((Status::BadRequest, "No model loaded".to_owned()))?;
+        let bytes: Vec<u8> = tokens
+            .iter()
+            .flat_map(|&token| inference::token_to_bytes(ctx, token))
+            .collect();
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    })
+    .await
+    .map(|content| Json(llamacpp::DetokenizeResponse { content }))
+    .map_err(error_response)
+}
+
+// needs the model to be loaded with embedding enabled, like the server example's --embedding
+#[rocket::post("/embedding", data = "<user_input>")]
+pub async fn server_embedding(
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &rocket::State<Metrics>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<llamacpp::EmbeddingRequest>,
+) -> Result<Json<llamacpp::EmbeddingResponse>, status::Custom<Json<ModelEventResponse>>> {
+    require_inference(&client).map_err(error_response)?;
+    let start = Instant::now();
+    let worker = Worker::current(state)
+        .await
+        .map_err(|e| error_response((Status::BadRequest, e)))?;
+    let (model, embedding) = {
+        let state = state.read().await;
+        (
+            state.current_model.clone(),
+            state
+                .loaded_model
+                .as_ref()
+                .is_some_and(|loaded_model| loaded_model.params.embedding),
+        )
+    };
+    if !embedding {
+        return Err(error_response((
+            Status::BadRequest,
+            "Model was not loaded with embedding enabled".to_owned(),
+        )));
+    }
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": model,
+        "api": "llama.cpp",
+    });
+    let result = start_embedding(&worker, metrics, user_input.0.content).await;
+    log_fields["duration_ms"] = json!(start.elapsed().as_secs_f64() * 1000.0);
+    match result {
+        Ok(embedding) => {
+            event(Level::Info, "embedding", log_fields);
+            Ok(Json(llamacpp::EmbeddingResponse { embedding }))
+        }
+        Err((status, e)) => {
+            log_fields["error"] = json!(e);
+            event(Level::Error, "embedding_failed", log_fields);
+            Err(error_response((status, e)))
+        }
+    }
+}
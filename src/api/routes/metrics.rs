@@ -0,0 +1,31 @@
+use rocket::{http::ContentType, tokio::sync::RwLock};
+use std::sync::Arc;
+
+use crate::{
+    api::{metrics::Metrics, MainState},
+    sys::llama_get_kv_cache_token_count,
+};
+
+#[rocket::get("/metrics")]
+pub async fn export_metrics(
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    metrics: &rocket::State<Metrics>,
+) -> (ContentType, String) {
+    if let Some(wrapped_ctx) = state.read().await.ctx.clone() {
+        // skip while the context is busy rather than waiting on a generation
+        if let Some(ctx) = wrapped_ctx
+            .try_lock()
+            .ok()
+            .as_ref()
+            .and_then(|ctx| ctx.ctx.as_ref())
+        {
+            metrics.set_kv_cache_tokens(
+                unsafe { llama_get_kv_cache_token_count(ctx.as_ptr()) } as i64
+            );
+        }
+    }
+    (
+        ContentType::parse_flexible(prometheus::TEXT_FORMAT).unwrap(),
+        metrics.render(),
+    )
+}
@@ -0,0 +1,7 @@
+pub mod batch;
+pub mod completions;
+pub mod conversations;
+pub mod llamacpp;
+pub mod metrics;
+pub mod models;
+pub mod ollama;
@@ -0,0 +1,91 @@
+use rocket::{
+    http::Status,
+    response::status,
+    serde::json::{json, Json},
+    tokio::sync::RwLock,
+};
+use std::sync::Arc;
+
+use crate::{
+    api::{
+        auth::Scope, error_response, load_by_name, logging::RequestId, metrics::Metrics,
+        ratelimit::Client, unload_model, MainState, ModelEventRequest, ModelEventResponse,
+    },
+    engine::models::{self, ModelDirectory},
+};
+
+#[rocket::get("/models", data = "<user_input>")]
+pub async fn change_model(
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    model_dir: &rocket::State<ModelDirectory>,
+    metrics: &rocket::State<Metrics>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<ModelEventRequest>,
+) -> Result<status::Accepted<Json<ModelEventResponse>>, status::Custom<Json<ModelEventResponse>>> {
+    let required_scope = match user_input.0 {
+        ModelEventRequest::LOAD { .. } | ModelEventRequest::UNLOAD => Scope::ADMIN,
+        ModelEventRequest::LIST | ModelEventRequest::CURRENT | ModelEventRequest::INFO => {
+            Scope::INFERENCE
+        }
+    };
+    if !client.api_key.allows(required_scope) {
+        return Err(status::Custom(
+            Status::Forbidden,
+            Json(ModelEventResponse::ERROR {
+                message: Some("API key is not permitted to perform this operation".to_owned()),
+            }),
+        ));
+    }
+    match user_input.0 {
+        ModelEventRequest::LOAD { message, params } => {
+            load_by_name(state, model_dir, metrics, &request_id.id, &message, params)
+                .await
+                .map(|_| status::Accepted(Json(ModelEventResponse::OK { message: None })))
+                .map_err(error_response)
+        }
+        ModelEventRequest::UNLOAD => {
+            unload_model(state, metrics, json!({ "request_id": request_id.id }))
+                .await
+                .map(|()| status::Accepted(Json(ModelEventResponse::OK { message: None })))
+                .map_err(error_response)
+        }
+        ModelEventRequest::LIST => {
+            let models = model_dir.list();
+            if !models.is_empty() {
+                Ok(status::Accepted(Json(ModelEventResponse::OKModels {
+                    message: models,
+                })))
+            } else {
+                Err(status::Custom(
+                    Status::InternalServerError,
+                    Json(ModelEventResponse::ERROR {
+                        message: Some("No models in directory".to_owned()),
+                    }),
+                ))
+            }
+        }
+        ModelEventRequest::CURRENT => Ok(status::Accepted(Json(ModelEventResponse::OK {
+            message: state.read().await.current_model.clone(),
+        }))),
+        ModelEventRequest::INFO => {
+            let state = state.read().await;
+            match state.loaded_model.clone() {
+                Some(mut loaded_model) => {
+                    loaded_model.resident_memory = models::resident_memory();
+                    loaded_model.keep_alive =
+                        state.keep_alive.map(|keep_alive| keep_alive.as_secs_f64());
+                    Ok(status::Accepted(Json(ModelEventResponse::OKInfo {
+                        message: loaded_model,
+                    })))
+                }
+                None => Err(status::Custom(
+                    Status::BadRequest,
+                    Json(ModelEventResponse::ERROR {
+                        message: Some("No model loaded".to_owned()),
+                    }),
+                )),
+            }
+        }
+    }
+}
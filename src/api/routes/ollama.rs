@@ -0,0 +1,373 @@
+use log::Level;
+use rocket::{
+    http::{ContentType, Status},
+    response::{status, stream::TextStream},
+    serde::json::{json, Json, Value},
+    tokio::sync::RwLock,
+    Either,
+};
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use crate::{
+    api::{
+        conversations::ChatFormat,
+        ensure_model, finish_completion,
+        jobs::Jobs,
+        logging::{self, event, RequestId},
+        metrics::Metrics,
+        ollama,
+        ratelimit::{Client, RateLimiter},
+        require_inference, start_completion, start_embedding, start_job, MainState,
+    },
+    config::ModelParams,
+    engine::{
+        inference::{Interrupt, Limits},
+        models::ModelDirectory,
+        tokenize_text, LlamaContext,
+    },
+};
+
+// ollama's /api/generate, streamed as one JSON object per line unless stream is false
+#[rocket::post("/generate", data = "<user_input>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn ollama_generate<'r>(
+    state: &'r rocket::State<Arc<RwLock<MainState>>>,
+    model_dir: &rocket::State<ModelDirectory>,
+    metrics: &'r rocket::State<Metrics>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    jobs: &rocket::State<Jobs>,
+    limits: &rocket::State<Limits>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<ollama::GenerateRequest>,
+) -> Result<
+    Either<Json<ollama::GenerateResponse>, (ContentType, TextStream![String + 'r])>,
+    status::Custom<Json<Value>>,
+> {
+    require_inference(&client).map_err(ollama::error)?;
+    let request = user_input.0;
+    let start = Instant::now();
+    let mut params = ollama::generation_params(&request.options)
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    params
+        .clamp(limits)
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    let name = ollama::model_name(&request.model).to_owned();
+    let (worker, load_time) = ensure_model(
+        state,
+        model_dir,
+        metrics,
+        &request_id.id,
+        &client,
+        &name,
+        ollama::model_params(&request.options),
+        request.keep_alive.as_ref(),
+    )
+    .await
+    .map_err(ollama::error)?;
+    // evaluated as given, in the draft and guidance contexts too
+    worker
+        .check_tokens(&request.context)
+        .map_err(ollama::error)?;
+    let model = request.model;
+    if request.prompt.is_empty() && request.context.is_empty() {
+        return Ok(Either::Left(Json(ollama::GenerateResponse::loaded(&model))));
+    }
+    let text = match request.system {
+        Some(system) if !request.raw => format!("{}\n{}", system, request.prompt),
+        _ => request.prompt,
+    };
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": name,
+        "stream": request.stream,
+        "api": "ollama",
+    });
+    if logging::log_prompts() {
+        log_fields["prompt"] = json!(text);
+    }
+    let job = start_job(jobs, &request_id.id, &client).map_err(ollama::error)?;
+    let interrupt = Interrupt::new(job.cancelled(), start, &params)
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    // the context of an earlier response already starts with the beginning of sentence token
+    let context = request.context;
+    let prompt = move |ctx: &LlamaContext| {
+        let add_bos = context.is_empty();
+        let mut tokens = context;
+        tokens.extend(tokenize_text(ctx, &text, add_bos)?);
+        Ok(tokens)
+    };
+    let (mut recv, handle) = start_completion(&worker, metrics, prompt, params, interrupt, None)
+        .await
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    let finish_metrics = metrics.inner().clone();
+    let finish_rate_limiter = rate_limiter.inner().clone();
+    let finished = rocket::tokio::spawn(async move {
+        finish_completion(
+            &finish_metrics,
+            &finish_rate_limiter,
+            &client,
+            start,
+            log_fields,
+            handle,
+        )
+        .await
+    });
+    if request.stream {
+        Ok(Either::Right((
+            ContentType::new("application", "x-ndjson"),
+            TextStream! {
+                let _job = job;
+                let mut first_token = true;
+                while let Some(piece) = recv.recv().await {
+                    if first_token {
+                        metrics.observe_first_token(start.elapsed());
+                        first_token = false;
+                    }
+                    yield ollama::line(&ollama::GenerateResponse::token(&model, piece));
+                }
+                match finished.await {
+                    Ok(Ok(completion)) => yield ollama::line(&ollama::GenerateResponse::done(
+                        &model,
+                        String::new(),
+                        completion,
+                        start.elapsed(),
+                        load_time,
+                    )),
+                    Ok(Err(e)) => yield ollama::line(&json!({ "error": e })),
+                    Err(_) => yield ollama::line(&json!({ "error": "Unable to complete: Thread panicked" })),
+                }
+            },
+        )))
+    } else {
+        if recv.recv().await.is_some() {
+            metrics.observe_first_token(start.elapsed());
+        }
+        while recv.recv().await.is_some() {}
+        match finished.await {
+            Ok(Ok(completion)) => Ok(Either::Left(Json(ollama::GenerateResponse::done(
+                &model,
+                completion.text.clone(),
+                completion,
+                start.elapsed(),
+                load_time,
+            )))),
+            Ok(Err(e)) => Err(ollama::error((Status::InternalServerError, e))),
+            Err(_) => Err(ollama::error((
+                Status::InternalServerError,
+                "Unable to complete: Thread panicked".to_owned(),
+            ))),
+        }
+    }
+}
+
+// ollama's /api/chat, messages are laid out with the default chat format
+#[rocket::post("/chat", data = "<user_input>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn ollama_chat<'r>(
+    state: &'r rocket::State<Arc<RwLock<MainState>>>,
+    model_dir: &rocket::State<ModelDirectory>,
+    metrics: &'r rocket::State<Metrics>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    jobs: &rocket::State<Jobs>,
+    limits: &rocket::State<Limits>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<ollama::ChatRequest>,
+) -> Result<
+    Either<Json<ollama::ChatResponse>, (ContentType, TextStream![String + 'r])>,
+    status::Custom<Json<Value>>,
+> {
+    require_inference(&client).map_err(ollama::error)?;
+    let request = user_input.0;
+    let start = Instant::now();
+    let format = ChatFormat::default();
+    let mut params = ollama::generation_params(&request.options)
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    params
+        .clamp(limits)
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    params.stop.push(format.stop());
+    let name = ollama::model_name(&request.model).to_owned();
+    let (worker, load_time) = ensure_model(
+        state,
+        model_dir,
+        metrics,
+        &request_id.id,
+        &client,
+        &name,
+        ollama::model_params(&request.options),
+        request.keep_alive.as_ref(),
+    )
+    .await
+    .map_err(ollama::error)?;
+    let model = request.model;
+    if request.messages.is_empty() {
+        return Ok(Either::Left(Json(ollama::ChatResponse::loaded(&model))));
+    }
+    let text = format.render(&request.messages);
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": name,
+        "stream": request.stream,
+        "api": "ollama",
+        "chat": true,
+    });
+    if logging::log_prompts() {
+        log_fields["prompt"] = json!(text);
+    }
+    let job = start_job(jobs, &request_id.id, &client).map_err(ollama::error)?;
+    let interrupt = Interrupt::new(job.cancelled(), start, &params)
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    let prompt = move |ctx: &LlamaContext| tokenize_text(ctx, &text, true);
+    let (mut recv, handle) = start_completion(&worker, metrics, prompt, params, interrupt, None)
+        .await
+        .map_err(|e| ollama::error((Status::BadRequest, e)))?;
+    let finish_metrics = metrics.inner().clone();
+    let finish_rate_limiter = rate_limiter.inner().clone();
+    let finished = rocket::tokio::spawn(async move {
+        finish_completion(
+            &finish_metrics,
+            &finish_rate_limiter,
+            &client,
+            start,
+            log_fields,
+            handle,
+        )
+        .await
+    });
+    if request.stream {
+        Ok(Either::Right((
+            ContentType::new("application", "x-ndjson"),
+            TextStream! {
+                let _job = job;
+                let mut first_token = true;
+                while let Some(piece) = recv.recv().await {
+                    if first_token {
+                        metrics.observe_first_token(start.elapsed());
+                        first_token = false;
+                    }
+                    yield ollama::line(&ollama::ChatResponse::token(&model, piece));
+                }
+                match finished.await {
+                    Ok(Ok(completion)) => yield ollama::line(&ollama::ChatResponse::done(
+                        &model,
+                        String::new(),
+                        &completion,
+                        start.elapsed(),
+                        load_time,
+                    )),
+                    Ok(Err(e)) => yield ollama::line(&json!({ "error": e })),
+                    Err(_) => yield ollama::line(&json!({ "error": "Unable to complete: Thread panicked" })),
+                }
+            },
+        )))
+    } else {
+        if recv.recv().await.is_some() {
+            metrics.observe_first_token(start.elapsed());
+        }
+        while recv.recv().await.is_some() {}
+        match finished.await {
+            Ok(Ok(completion)) => Ok(Either::Left(Json(ollama::ChatResponse::done(
+                &model,
+                completion.text.trim().to_owned(),
+                &completion,
+                start.elapsed(),
+                load_time,
+            )))),
+            Ok(Err(e)) => Err(ollama::error((Status::InternalServerError, e))),
+            Err(_) => Err(ollama::error((
+                Status::InternalServerError,
+                "Unable to complete: Thread panicked".to_owned(),
+            ))),
+        }
+    }
+}
+
+// ollama's /api/tags, every model in the model directory
+#[rocket::get("/tags")]
+pub fn ollama_tags(
+    model_dir: &rocket::State<ModelDirectory>,
+    client: Client,
+) -> Result<Json<ollama::Tags>, status::Custom<Json<Value>>> {
+    require_inference(&client).map_err(ollama::error)?;
+    let models = model_dir
+        .list()
+        .iter()
+        .map(|info| {
+            let modified = model_dir
+                .resolve(&info.name)
+                .and_then(|path| path.metadata().ok())
+                .and_then(|metadata| metadata.modified().ok())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            ollama::TagsModel::new(info, modified)
+        })
+        .collect();
+    Ok(Json(ollama::Tags { models }))
+}
+
+// ollama's /api/show, read from the file header without loading the model
+#[rocket::post("/show", data = "<user_input>")]
+pub fn ollama_show(
+    model_dir: &rocket::State<ModelDirectory>,
+    client: Client,
+    user_input: Json<ollama::ShowRequest>,
+) -> Result<Json<ollama::ShowResponse>, status::Custom<Json<Value>>> {
+    require_inference(&client).map_err(ollama::error)?;
+    model_dir
+        .find(ollama::model_name(&user_input.name))
+        .map(|info| Json(ollama::ShowResponse::from(&info)))
+        .ok_or_else(|| ollama::error((Status::NotFound, "Invalid model name".to_owned())))
+}
+
+// ollama's /api/embeddings, the model is reloaded with embedding enabled if it was loaded without
+#[rocket::post("/embeddings", data = "<user_input>")]
+pub async fn ollama_embeddings(
+    state: &rocket::State<Arc<RwLock<MainState>>>,
+    model_dir: &rocket::State<ModelDirectory>,
+    metrics: &rocket::State<Metrics>,
+    request_id: &RequestId,
+    client: Client,
+    user_input: Json<ollama::EmbeddingsRequest>,
+) -> Result<Json<ollama::EmbeddingsResponse>, status::Custom<Json<Value>>> {
+    require_inference(&client).map_err(ollama::error)?;
+    let request = user_input.0;
+    let start = Instant::now();
+    let name = ollama::model_name(&request.model).to_owned();
+    let (worker, _) = ensure_model(
+        state,
+        model_dir,
+        metrics,
+        &request_id.id,
+        &client,
+        &name,
+        ModelParams {
+            embedding: Some(true),
+            ..ollama::model_params(&request.options)
+        },
+        request.keep_alive.as_ref(),
+    )
+    .await
+    .map_err(ollama::error)?;
+    let mut log_fields = json!({
+        "request_id": request_id.id,
+        "model": name,
+        "api": "ollama",
+    });
+    let result = start_embedding(&worker, metrics, request.prompt).await;
+    log_fields["duration_ms"] = json!(start.elapsed().as_secs_f64() * 1000.0);
+    match result {
+        Ok(embedding) => {
+            event(Level::Info, "embedding", log_fields);
+            Ok(Json(ollama::EmbeddingsResponse { embedding }))
+        }
+        Err((status, e)) => {
+            log_fields["error"] = json!(e);
+            event(Level::Error, "embedding_failed", log_fields);
+            Err(ollama::error((status, e)))
+        }
+    }
+}
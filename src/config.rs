@@ -0,0 +1,235 @@
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, Utc};
+use clap::Parser;
+use rocket::tokio::fs::read_to_string;
+use serde::Deserialize;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::engine::models::KeepAlive;
+
+// server-wide defaults from the command line, model parameters override them
+#[derive(Clone)]
+pub struct LoadParams {
+    pub path_to_model_dir: PathBuf,
+    pub context_size: Option<i32>,
+    pub gpu_offload: Option<i32>,
+    pub seed: Option<i32>,
+    pub kv_in_f16: Option<bool>,
+    pub pin_memory: Option<bool>,
+    pub no_swap: Option<bool>,
+    pub idle_timeout: Option<KeepAlive>,
+    pub n_threads: i32,
+}
+
+// per-model overrides for llama_context_params, unset fields fall through to the next level
+#[derive(Clone, Default, Deserialize)]
+pub struct ModelParams {
+    pub n_ctx: Option<i32>,
+    pub n_gpu_layers: Option<i32>,
+    pub seed: Option<i32>,
+    pub f16_kv: Option<bool>,
+    pub use_mmap: Option<bool>,
+    pub use_mlock: Option<bool>,
+    pub vocab_only: Option<bool>,
+    // applied in order after loading, relative to the model directory
+    pub lora_adapters: Option<Vec<String>>,
+    // higher quality model the adapters are applied against, relative to the model directory
+    pub lora_base: Option<String>,
+    // smaller model with the same vocabulary for speculative decoding, relative to the model directory
+    pub draft_model: Option<String>,
+    // tokens the draft model proposes per llama_eval call (default 4)
+    pub draft_tokens: Option<usize>,
+    // loads the model a second time to evaluate negative prompts, weights are shared when memory mapped
+    pub guidance: Option<bool>,
+    // keeps the hidden state of the last evaluated token for embeddings
+    pub embedding: Option<bool>,
+    // unloads the model once it has been idle this long, requests can override it with keep_alive
+    pub idle_timeout: Option<KeepAlive>,
+}
+
+impl ModelParams {
+    pub fn or(self, fallback: ModelParams) -> ModelParams {
+        ModelParams {
+            n_ctx: self.n_ctx.or(fallback.n_ctx),
+            n_gpu_layers: self.n_gpu_layers.or(fallback.n_gpu_layers),
+            seed: self.seed.or(fallback.seed),
+            f16_kv: self.f16_kv.or(fallback.f16_kv),
+            use_mmap: self.use_mmap.or(fallback.use_mmap),
+            use_mlock: self.use_mlock.or(fallback.use_mlock),
+            vocab_only: self.vocab_only.or(fallback.vocab_only),
+            lora_adapters: self.lora_adapters.or(fallback.lora_adapters),
+            lora_base: self.lora_base.or(fallback.lora_base),
+            draft_model: self.draft_model.or(fallback.draft_model),
+            draft_tokens: self.draft_tokens.or(fallback.draft_tokens),
+            guidance: self.guidance.or(fallback.guidance),
+            embedding: self.embedding.or(fallback.embedding),
+            idle_timeout: self.idle_timeout.or(fallback.idle_timeout),
+        }
+    }
+}
+
+impl From<&LoadParams> for ModelParams {
+    fn from(load_params: &LoadParams) -> Self {
+        ModelParams {
+            n_ctx: load_params.context_size,
+            n_gpu_layers: load_params.gpu_offload,
+            seed: load_params.seed,
+            f16_kv: load_params.kv_in_f16,
+            use_mmap: load_params.pin_memory,
+            use_mlock: load_params.no_swap,
+            vocab_only: None,
+            lora_adapters: None,
+            lora_base: None,
+            draft_model: None,
+            draft_tokens: None,
+            guidance: None,
+            embedding: None,
+            idle_timeout: load_params.idle_timeout.clone(),
+        }
+    }
+}
+
+// sidecar file next to the model, e.g. model.bin.json for model.bin
+pub async fn read_model_params(path_to_model: &Path) -> Result<ModelParams, String> {
+    let mut sidecar = path_to_model.as_os_str().to_owned();
+    sidecar.push(".json");
+    match read_to_string(&sidecar).await {
+        Ok(contents) => rocket::serde::json::from_str(&contents)
+            .map_err(|e| format!("Invalid model parameters file: {}", e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(ModelParams::default()),
+        Err(e) => Err(format!("Unable to read model parameters file: {}", e)),
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum LogLevel {
+    OFF,
+    ERROR,
+    WARN,
+    INFO,
+    DEBUG,
+    TRACE,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum QuotaPeriod {
+    DAY,
+    MONTH,
+}
+
+impl QuotaPeriod {
+    // identifies the period now in progress, usage is reset when it changes
+    pub fn current(&self) -> String {
+        let now = Utc::now();
+        match self {
+            QuotaPeriod::DAY => now.format("%Y-%m-%d").to_string(),
+            QuotaPeriod::MONTH => now.format("%Y-%m").to_string(),
+        }
+    }
+
+    pub fn seconds_remaining(&self) -> u64 {
+        let now = Utc::now().naive_utc();
+        let today = now.date();
+        let next = match self {
+            QuotaPeriod::DAY => today + ChronoDuration::days(1),
+            QuotaPeriod::MONTH => {
+                if today.month() == 12 {
+                    NaiveDate::from_ymd_opt(today.year() + 1, 1, 1).unwrap()
+                } else {
+                    NaiveDate::from_ymd_opt(today.year(), today.month() + 1, 1).unwrap()
+                }
+            }
+        };
+        (next.and_hms_opt(0, 0, 0).unwrap() - now)
+            .num_seconds()
+            .max(1) as u64
+    }
+}
+
+// the command line of the server binary
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLI {
+    // Path to model directory
+    pub model_dir: PathBuf,
+    #[arg(long)]
+    // Context size [not designed to work for values greater than 2048] (default 2048)
+    pub ctx_size: Option<i32>,
+    #[arg(long)]
+    // Number of layers to offload to GPU (default 0)
+    pub gpu_offload_layers: Option<i32>,
+    #[arg(long)]
+    // Random seed (using GPU does not guarantee reproducible results) (default -1)
+    pub seed: Option<i32>,
+    #[arg(long)]
+    // Use 16-bit floats for KV store (default true)
+    pub use_f16: Option<bool>,
+    #[arg(long)]
+    // Pin model in memory for faster access (default true)
+    pub use_mmap: Option<bool>,
+    #[arg(long)]
+    // Prevent mapped memory from going to disk (default false) (will cause errors if memory is insufficient)
+    pub use_mlock: Option<bool>,
+    #[arg(long)]
+    // Number of threads used for evaluation (default number of available cores)
+    pub threads: Option<i32>,
+    #[arg(long)]
+    // API key with inference and admin scopes, can be repeated (authentication is disabled if no keys are given)
+    pub api_key: Vec<String>,
+    #[arg(long)]
+    // JSON file with a list of {"key": ..., "scopes": ["inference", "admin"]} entries
+    pub api_keys_file: Option<PathBuf>,
+    #[arg(long)]
+    // Requests per minute allowed for each API key and client address (default unlimited)
+    pub requests_per_minute: Option<u32>,
+    #[arg(long)]
+    // Generated tokens allowed for each API key and client address per quota period (default unlimited)
+    pub token_quota: Option<u64>,
+    #[arg(long, value_enum, default_value = "month")]
+    // Period after which token usage is reset
+    pub quota_period: QuotaPeriod,
+    #[arg(long)]
+    // File to persist token usage across restarts
+    pub usage_file: Option<PathBuf>,
+    #[arg(long)]
+    // Maximum prompt tokens per completion, requests asking for more are clamped (default context size)
+    pub max_prompt_tokens: Option<usize>,
+    #[arg(long)]
+    // Maximum generated tokens per completion, requests asking for more are clamped (default unlimited)
+    pub max_tokens: Option<usize>,
+    #[arg(long)]
+    // Seconds a completion may take including time queued, requests asking for more are clamped (default unlimited)
+    pub timeout: Option<f64>,
+    #[arg(long)]
+    // Seconds until the first generated token including time queued, requests asking for more are clamped (default unlimited)
+    pub first_token_timeout: Option<f64>,
+    #[arg(long, default_value = "8")]
+    // Maximum completions returned per request (n), requests asking for more are clamped
+    pub max_n: usize,
+    #[arg(long, default_value = "16")]
+    // Maximum completions generated per request (best_of), requests asking for more are clamped
+    pub max_best_of: usize,
+    #[arg(long, default_value = "8")]
+    // Maximum beams kept by beam search (beam_width), requests asking for more are clamped
+    pub max_beam_width: usize,
+    #[arg(long, default_value = "64")]
+    // Maximum prompts in a batch request, larger batches are rejected
+    pub max_batch_size: usize,
+    #[arg(long)]
+    // Directory for stored conversations and their session files (conversations are disabled if not given)
+    pub data_dir: Option<PathBuf>,
+    #[arg(long)]
+    // Unload the model after it has been idle this long, in seconds or e.g. 10m (default never) (model parameters and requests can override it)
+    pub idle_timeout: Option<String>,
+    #[arg(long)]
+    // Model to load and warm up before serving, can be repeated (only the last stays loaded, the others are left in the page cache)
+    pub preload: Vec<String>,
+    #[arg(long, value_enum, default_value = "info")]
+    // Minimum level of log lines written to stderr
+    pub log_level: LogLevel,
+    #[arg(long)]
+    // Include prompt text in completion logs
+    pub log_prompts: bool,
+}
@@ -0,0 +1,238 @@
+use serde::Deserialize;
+use std::slice;
+
+use crate::{
+    engine::inference::{
+        copy_state, eval, token_to_bytes, FinishReason, GenerationParams, Interrupt,
+    },
+    engine::LlamaContext,
+    sys::{
+        llama_get_logits, llama_n_ctx, llama_n_vocab, llama_set_state_data, llama_time_us,
+        llama_token, llama_token_eos,
+    },
+};
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct BeamParams {
+    // beams kept after every step
+    pub beam_width: usize,
+    // scores are the cumulative logprob divided by length to this power, higher favours longer beams
+    pub length_penalty: f32,
+    // return every final beam with its score, not only the best one
+    pub return_beams: bool,
+}
+
+impl Default for BeamParams {
+    fn default() -> Self {
+        BeamParams {
+            beam_width: 4,
+            length_penalty: 1.0,
+            return_beams: false,
+        }
+    }
+}
+
+pub struct Beam {
+    pub tokens: Vec<llama_token>,
+    pub text: String,
+    pub logprob: f64,
+    pub score: f64,
+    pub finish_reason: FinishReason,
+}
+
+// a beam still being extended, state is the context after evaluating its tokens
+struct LiveBeam {
+    tokens: Vec<llama_token>,
+    logprob: f64,
+    state: Vec<u8>,
+    // most likely next tokens, read from the logits before the context moved on
+    next: Vec<(llama_token, f32)>,
+}
+
+pub struct BeamSearch {
+    // best first
+    pub beams: Vec<Beam>,
+    pub n_eval: usize,
+    pub eval_us: i64,
+    pub expand_us: i64,
+}
+
+// the n most likely next tokens under the logits of the last llama_eval call
+fn top_tokens(ctx: &LlamaContext, n: usize) -> Vec<(llama_token, f32)> {
+    let n_vocab = unsafe { llama_n_vocab(ctx.as_ptr()) } as usize;
+    let logits = unsafe { slice::from_raw_parts(llama_get_logits(ctx.as_ptr()), n_vocab) };
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits
+        .iter()
+        .map(|logit| (logit - max).exp())
+        .sum::<f32>()
+        .ln();
+    let mut res: Vec<(llama_token, f32)> = logits
+        .iter()
+        .enumerate()
+        .map(|(id, logit)| (id as llama_token, logit - max - log_sum))
+        .collect();
+    let n = n.min(res.len());
+    res.select_nth_unstable_by(n.saturating_sub(1), |a, b| b.1.total_cmp(&a.1));
+    res.truncate(n);
+    res
+}
+
+fn decode(ctx: &LlamaContext, tokens: &[llama_token]) -> String {
+    let bytes: Vec<u8> = tokens
+        .iter()
+        .flat_map(|&token| token_to_bytes(ctx, token))
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn finish(
+    text: String,
+    tokens: Vec<llama_token>,
+    logprob: f64,
+    finish_reason: FinishReason,
+    length_penalty: f32,
+) -> Beam {
+    let score = logprob / (tokens.len().max(1) as f64).powf(length_penalty as f64);
+    Beam {
+        tokens,
+        text,
+        logprob,
+        score,
+        finish_reason,
+    }
+}
+
+// continues from the logits of the prompt eval, every step expands each beam by its most likely tokens
+// and keeps the beam_width best, restoring the parent's snapshot before evaluating each child
+// snapshots hold the KV cache, so memory grows with beam_width and the length of the prompt
+// stops once beam_width beams have finished, kv_tokens follows whichever beam was evaluated last
+pub fn beam_search(
+    ctx: &mut LlamaContext,
+    kv_tokens: &mut Vec<llama_token>,
+    prompt_tokens: &[llama_token],
+    params: &GenerationParams,
+    n_threads: i32,
+    interrupt: &Interrupt,
+) -> Result<BeamSearch, String> {
+    let width = params.beam.beam_width.max(1);
+    let length_penalty = params.beam.length_penalty;
+    let n_ctx = unsafe { llama_n_ctx(ctx.as_ptr()) } as usize;
+    let eos = unsafe { llama_token_eos() };
+    let mut n_eval = 0;
+    let mut eval_us = 0;
+    let mut expand_us = 0;
+    let t_start = unsafe { llama_time_us() };
+    let mut live = vec![LiveBeam {
+        tokens: Vec::new(),
+        logprob: 0.0,
+        state: copy_state(ctx),
+        next: top_tokens(ctx, width),
+    }];
+    expand_us += unsafe { llama_time_us() } - t_start;
+    // the beam the context currently holds, so its first child skips restoring
+    let mut held = Some(0);
+    let mut finished: Vec<Beam> = Vec::new();
+    while finished.len() < width && !live.is_empty() {
+        // live beams all have the same length
+        let step = live[0].tokens.len();
+        let stop_reason = if step >= params.max_tokens || prompt_tokens.len() + step >= n_ctx {
+            Some(FinishReason::LENGTH)
+        } else {
+            interrupt.check(step)
+        };
+        if let Some(finish_reason) = stop_reason {
+            finished.extend(live.drain(..).map(|beam| {
+                let text = decode(ctx, &beam.tokens);
+                finish(
+                    text,
+                    beam.tokens,
+                    beam.logprob,
+                    finish_reason,
+                    length_penalty,
+                )
+            }));
+            break;
+        }
+        let mut candidates: Vec<(usize, llama_token, f64)> = live
+            .iter()
+            .enumerate()
+            .flat_map(|(i, beam)| {
+                beam.next
+                    .iter()
+                    .map(move |&(token, logprob)| (i, token, beam.logprob + logprob as f64))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+        let mut next_live: Vec<LiveBeam> = Vec::with_capacity(width);
+        for (parent, token, logprob) in candidates {
+            if next_live.len() >= width {
+                break;
+            }
+            let parent_beam = &live[parent];
+            if token == eos {
+                let text = decode(ctx, &parent_beam.tokens);
+                finished.push(finish(
+                    text,
+                    parent_beam.tokens.clone(),
+                    logprob,
+                    FinishReason::STOP,
+                    length_penalty,
+                ));
+                continue;
+            }
+            let mut tokens = parent_beam.tokens.clone();
+            tokens.push(token);
+            let mut text = decode(ctx, &tokens);
+            if let Some(pos) = params
+                .stop
+                .iter()
+                .filter(|s| !s.is_empty())
+                .filter_map(|s| text.find(s.as_str()))
+                .min()
+            {
+                text.truncate(pos);
+                finished.push(finish(
+                    text,
+                    tokens,
+                    logprob,
+                    FinishReason::STOP,
+                    length_penalty,
+                ));
+                continue;
+            }
+            let t_start = unsafe { llama_time_us() };
+            if held != Some(parent) {
+                // llama_set_state_data only reads from the buffer despite the mutable pointer
+                unsafe {
+                    llama_set_state_data(ctx.as_ptr(), parent_beam.state.as_ptr() as *mut u8)
+                };
+            }
+            held = None;
+            kv_tokens.truncate(prompt_tokens.len());
+            kv_tokens.extend_from_slice(&parent_beam.tokens);
+            eval(ctx, &[token], prompt_tokens.len() + step, n_threads)?;
+            kv_tokens.push(token);
+            eval_us += unsafe { llama_time_us() } - t_start;
+            n_eval += 1;
+            let t_start = unsafe { llama_time_us() };
+            next_live.push(LiveBeam {
+                tokens,
+                logprob,
+                state: copy_state(ctx),
+                next: top_tokens(ctx, width),
+            });
+            expand_us += unsafe { llama_time_us() } - t_start;
+        }
+        held = next_live.len().checked_sub(1);
+        live = next_live;
+    }
+    finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(BeamSearch {
+        beams: finished,
+        n_eval,
+        eval_us,
+        expand_us,
+    })
+}
@@ -0,0 +1,70 @@
+use std::slice;
+
+use crate::{
+    engine::{inference::catch_up, LlamaContext},
+    sys::{llama_get_logits, llama_n_ctx, llama_n_vocab, llama_time_us, llama_token},
+};
+
+// a second context of the loaded model that evaluates negative prompts
+pub struct GuidanceContext {
+    pub ctx: LlamaContext,
+    // tokens in its KV cache, like kv_tokens of ModelContext
+    pub kv_tokens: Vec<llama_token>,
+}
+
+// classifier-free guidance for one request
+pub struct Guidance<'a> {
+    pub context: &'a mut GuidanceContext,
+    pub negative_tokens: Vec<llama_token>,
+    pub scale: f32,
+    pub guidance_us: i64,
+}
+
+fn log_softmax(logits: &mut [f32]) {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits
+        .iter()
+        .map(|logit| (logit - max).exp())
+        .sum::<f32>()
+        .ln();
+    for logit in logits {
+        *logit -= max + log_sum;
+    }
+}
+
+impl Guidance<'_> {
+    pub fn n_ctx(&self) -> usize {
+        unsafe { llama_n_ctx(self.context.ctx.as_ptr()) as usize }
+    }
+
+    // evaluates the negative prompt followed by the generated tokens, then moves ctx's logits
+    // away from the negative ones: neg + scale * (logits - neg), so a scale of 1 changes nothing
+    pub fn apply(
+        &mut self,
+        ctx: &mut LlamaContext,
+        generated: &[llama_token],
+        n_threads: i32,
+    ) -> Result<(), String> {
+        let t_start = unsafe { llama_time_us() };
+        let mut tokens = self.negative_tokens.clone();
+        tokens.extend_from_slice(generated);
+        catch_up(
+            &mut self.context.ctx,
+            &mut self.context.kv_tokens,
+            &tokens,
+            n_threads,
+        )?;
+        let n_vocab = unsafe { llama_n_vocab(ctx.as_ptr()) } as usize;
+        let logits = unsafe { slice::from_raw_parts_mut(llama_get_logits(ctx.as_ptr()), n_vocab) };
+        let mut negative =
+            unsafe { slice::from_raw_parts(llama_get_logits(self.context.ctx.as_ptr()), n_vocab) }
+                .to_vec();
+        log_softmax(logits);
+        log_softmax(&mut negative);
+        for (logit, negative) in logits.iter_mut().zip(negative) {
+            *logit = negative + self.scale * (*logit - negative);
+        }
+        self.guidance_us += unsafe { llama_time_us() } - t_start;
+        Ok(())
+    }
+}
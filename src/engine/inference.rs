@@ -0,0 +1,934 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CStr,
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    engine::{
+        beam::{beam_search, BeamParams},
+        guidance::{Guidance, GuidanceContext},
+        models::KeepAlive,
+        speculative::{propose, DraftContext, SpeculativeTimings},
+        tokenize_text, LlamaContext,
+    },
+    sampling::{copy_logits, logits_logprob, sample, set_seed, token_logprob, SamplingParams},
+    sys::{
+        llama_copy_state_data, llama_eval, llama_get_embeddings, llama_get_state_size, llama_n_ctx,
+        llama_n_embd, llama_set_state_data, llama_time_us, llama_token, llama_token_eos,
+        llama_token_to_str,
+    },
+};
+
+const BATCH_SIZE: usize = 512;
+
+fn default_max_tokens() -> usize {
+    128
+}
+
+fn default_n() -> usize {
+    1
+}
+
+fn default_cfg_scale() -> f32 {
+    1.0
+}
+
+#[derive(Clone, Deserialize)]
+pub struct GenerationParams {
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    // rejects longer prompts instead of evaluating them
+    pub max_prompt_tokens: Option<usize>,
+    // seconds from accepting the request, including time spent queued
+    pub timeout: Option<f64>,
+    pub first_token_timeout: Option<f64>,
+    // alternative completions returned
+    #[serde(default = "default_n")]
+    pub n: usize,
+    // completions generated, the n with the highest cumulative logprob are returned (default n)
+    pub best_of: Option<usize>,
+    #[serde(default)]
+    pub decoding: Decoding,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+    #[serde(flatten)]
+    pub beam: BeamParams,
+    // classifier-free guidance, sampling is steered away from what follows this prompt
+    pub negative_prompt: Option<String>,
+    // 1 leaves the logits unchanged, higher values steer further
+    #[serde(default = "default_cfg_scale")]
+    pub cfg_scale: f32,
+    // how long the model stays loaded once idle after this request, replaces its idle timeout
+    pub keep_alive: Option<KeepAlive>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decoding {
+    #[default]
+    SAMPLE,
+    // ignores the sampling parameters, n, best_of and the negative prompt
+    BEAM,
+}
+
+// server-wide ceilings, client-supplied values above them are lowered
+#[derive(Clone, Default)]
+pub struct Limits {
+    pub max_prompt_tokens: Option<usize>,
+    pub max_tokens: Option<usize>,
+    pub timeout: Option<f64>,
+    pub first_token_timeout: Option<f64>,
+    pub max_n: Option<usize>,
+    pub max_best_of: Option<usize>,
+    pub max_beam_width: Option<usize>,
+    // prompts in a single batch request, larger batches are rejected
+    pub max_batch_size: Option<usize>,
+}
+
+fn lowest<T: PartialOrd>(value: Option<T>, limit: Option<T>) -> Option<T> {
+    match (value, limit) {
+        (Some(value), Some(limit)) => Some(if value < limit { value } else { limit }),
+        (value, limit) => value.or(limit),
+    }
+}
+
+// a timeout from the client as an instant, Err if it does not fit in a Duration or an Instant
+fn deadline(start: Instant, seconds: f64) -> Result<Instant, String> {
+    Duration::try_from_secs_f64(seconds.max(0.0))
+        .ok()
+        .and_then(|timeout| start.checked_add(timeout))
+        .ok_or_else(|| format!("Timeout is too long: {}", seconds))
+}
+
+impl GenerationParams {
+    pub fn clamp(&mut self, limits: &Limits) -> Result<(), String> {
+        self.max_prompt_tokens = lowest(self.max_prompt_tokens, limits.max_prompt_tokens);
+        self.max_tokens = lowest(Some(self.max_tokens), limits.max_tokens).unwrap();
+        self.timeout = lowest(self.timeout, limits.timeout);
+        self.first_token_timeout = lowest(self.first_token_timeout, limits.first_token_timeout);
+        if self.n == 0 {
+            return Err("n must be at least 1".to_owned());
+        }
+        if self.best_of.is_some_and(|best_of| best_of < self.n) {
+            return Err("best_of must be at least n".to_owned());
+        }
+        // the negative prompt is only tokenized once generation has started
+        if self
+            .negative_prompt
+            .as_ref()
+            .is_some_and(|negative_prompt| negative_prompt.contains('\0'))
+        {
+            return Err("Negative prompt contains a null character".to_owned());
+        }
+        // n is lowered to max_best_of too, so best_of stays at least n
+        self.n = lowest(lowest(Some(self.n), limits.max_n), limits.max_best_of).unwrap();
+        self.best_of = self
+            .best_of
+            .map(|best_of| lowest(Some(best_of), limits.max_best_of).unwrap());
+        self.beam.beam_width = lowest(Some(self.beam.beam_width), limits.max_beam_width).unwrap();
+        // rejected here rather than when the request has already been queued
+        for seconds in [self.timeout, self.first_token_timeout]
+            .into_iter()
+            .flatten()
+        {
+            deadline(Instant::now(), seconds)?;
+        }
+        Ok(())
+    }
+
+    // branches generated from the prompt
+    pub fn choices(&self) -> usize {
+        self.best_of.unwrap_or(self.n).max(self.n)
+    }
+
+    // text can only be streamed while it is generated when there is a single sampled branch
+    pub fn streams(&self) -> bool {
+        self.decoding == Decoding::SAMPLE && self.choices() == 1
+    }
+}
+
+// reasons to stop early, checked before every llama_eval call
+pub struct Interrupt {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+    first_token_deadline: Option<Instant>,
+}
+
+impl Interrupt {
+    pub fn new(
+        cancelled: Arc<AtomicBool>,
+        start: Instant,
+        params: &GenerationParams,
+    ) -> Result<Interrupt, String> {
+        let after =
+            |seconds: Option<f64>| seconds.map(|seconds| deadline(start, seconds)).transpose();
+        Ok(Interrupt {
+            cancelled,
+            deadline: after(params.timeout)?,
+            first_token_deadline: after(params.first_token_timeout)?,
+        })
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // the earliest deadline that applies before any token is generated
+    pub fn queue_deadline(&self) -> Option<Instant> {
+        lowest(self.deadline, self.first_token_deadline)
+    }
+
+    pub fn check(&self, generated: usize) -> Option<FinishReason> {
+        let now = Instant::now();
+        if self.is_cancelled() {
+            Some(FinishReason::CANCELLED)
+        } else if self.deadline.is_some_and(|deadline| now >= deadline)
+            || (generated == 0
+                && self
+                    .first_token_deadline
+                    .is_some_and(|deadline| now >= deadline))
+        {
+            Some(FinishReason::TIMEOUT)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    STOP,
+    LENGTH,
+    CANCELLED,
+    TIMEOUT,
+}
+
+// what llama_print_timings would print for this request, in milliseconds
+#[derive(Clone, Default, Serialize)]
+pub struct Timings {
+    pub load_ms: f64,
+    pub prompt_tokens: usize,
+    // prompt tokens reused from the KV cache instead of evaluated
+    pub cached_tokens: usize,
+    pub prompt_eval_ms: f64,
+    pub prompt_tokens_per_second: f64,
+    pub generated_tokens: usize,
+    pub eval_ms: f64,
+    pub generated_tokens_per_second: f64,
+    pub sample_ms: f64,
+    // only with a draft model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speculative: Option<SpeculativeTimings>,
+    // evaluating the negative prompt and generated tokens in the guidance context
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guidance_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct Choice {
+    pub message: String,
+    pub finish_reason: FinishReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i32>,
+    // sum of the log probabilities of the sampled tokens, before sampling adjusted them
+    pub logprob: f64,
+    // beams only, the logprob after the length penalty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+pub struct Completion {
+    pub text: String,
+    // the prompt followed by every sampled token, for continuing the conversation
+    pub tokens: Vec<llama_token>,
+    // tokens held in the KV cache once generation stopped
+    pub n_past: usize,
+    // rng seed used for sampling, none if nothing was sampled
+    pub seed: Option<i32>,
+    pub finish_reason: FinishReason,
+    // the stop sequence sampling ended on, none when it ended for another reason or with beam search
+    pub stop_word: Option<String>,
+    // every returned completion, best first, when more than one was asked for
+    pub choices: Vec<Choice>,
+    pub timings: Timings,
+}
+
+fn per_second(tokens: usize, us: i64) -> f64 {
+    if us > 0 {
+        tokens as f64 * 1e6 / us as f64
+    } else {
+        0.0
+    }
+}
+
+pub fn eval(
+    ctx: &mut LlamaContext,
+    tokens: &[llama_token],
+    n_past: usize,
+    n_threads: i32,
+) -> Result<(), String> {
+    for (i, batch) in tokens.chunks(BATCH_SIZE).enumerate() {
+        let res = unsafe {
+            llama_eval(
+                ctx.as_ptr(),
+                batch.as_ptr(),
+                batch.len() as i32,
+                (n_past + i * BATCH_SIZE) as i32,
+                n_threads,
+            )
+        };
+        if res != 0 {
+            return Err("Failed to evaluate tokens".to_owned());
+        }
+    }
+    Ok(())
+}
+
+// evaluates what kv_tokens does not share with tokens, leaving the logits after the last of them
+pub fn catch_up(
+    ctx: &mut LlamaContext,
+    kv_tokens: &mut Vec<llama_token>,
+    tokens: &[llama_token],
+    n_threads: i32,
+) -> Result<(), String> {
+    // at least one token has to be evaluated to get logits
+    let n_cached = kv_tokens
+        .iter()
+        .zip(tokens)
+        .take_while(|(cached, token)| cached == token)
+        .count()
+        .min(tokens.len().saturating_sub(1));
+    kv_tokens.truncate(n_cached);
+    eval(ctx, &tokens[n_cached..], n_cached, n_threads)?;
+    kv_tokens.extend_from_slice(&tokens[n_cached..]);
+    Ok(())
+}
+
+// hidden state after the last of tokens, the context has to be created with embedding enabled
+pub fn embed(
+    ctx: &mut LlamaContext,
+    kv_tokens: &mut Vec<llama_token>,
+    tokens: &[llama_token],
+    n_threads: i32,
+) -> Result<Vec<f32>, String> {
+    let n_ctx = unsafe { llama_n_ctx(ctx.as_ptr()) } as usize;
+    if tokens.len() > n_ctx {
+        return Err(format!(
+            "Prompt is too long ({} tokens, context size is {})",
+            tokens.len(),
+            n_ctx
+        ));
+    }
+    catch_up(ctx, kv_tokens, tokens, n_threads)?;
+    let embeddings = unsafe { llama_get_embeddings(ctx.as_ptr()) };
+    if embeddings.is_null() {
+        return Err("Model was not loaded with embedding enabled".to_owned());
+    }
+    let n_embd = unsafe { llama_n_embd(ctx.as_ptr()) } as usize;
+    Ok(unsafe { slice::from_raw_parts(embeddings, n_embd) }.to_vec())
+}
+
+pub fn token_to_bytes(ctx: &LlamaContext, token: llama_token) -> Vec<u8> {
+    unsafe { CStr::from_ptr(llama_token_to_str(ctx.as_ptr(), token)) }
+        .to_bytes()
+        .to_vec()
+}
+
+// length of the longest suffix of text that could still grow into a stop sequence
+fn partial_stop_len(text: &str, stop: &[String]) -> usize {
+    stop.iter()
+        .flat_map(|s| {
+            (1..s.len())
+                .rev()
+                .find(|&n| s.is_char_boundary(n) && text.ends_with(&s[..n]))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+// one sampled continuation of the prompt
+struct Branch {
+    text: String,
+    sampled: Vec<llama_token>,
+    finish_reason: FinishReason,
+    stop_word: Option<String>,
+    logprob: f64,
+    n_eval: usize,
+    eval_us: i64,
+    sample_us: i64,
+}
+
+// text of a branch, held back while it could still be part of a stop sequence
+#[derive(Default)]
+struct Output {
+    text: String,
+    // tokens can end partway through a multi-byte character
+    pending: Vec<u8>,
+    emitted: usize,
+}
+
+impl Output {
+    // the stop sequence once one was found, the text is cut before it
+    fn push(
+        &mut self,
+        bytes: &[u8],
+        stop: &[String],
+        on_token: &mut impl FnMut(&str),
+    ) -> Option<String> {
+        self.pending.extend(bytes);
+        match std::str::from_utf8(&self.pending) {
+            Ok(piece) => self.text.push_str(piece),
+            Err(e) if e.error_len().is_none() => return None,
+            Err(_) => self.text.push_str(&String::from_utf8_lossy(&self.pending)),
+        }
+        self.pending.clear();
+        if let Some((pos, stop)) = stop
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| Some((self.text[self.emitted..].find(s.as_str())?, s)))
+            .min_by_key(|(pos, _)| *pos)
+        {
+            self.text.truncate(self.emitted + pos);
+            return Some(stop.clone());
+        }
+        let safe = self.text.len() - partial_stop_len(&self.text[self.emitted..], stop);
+        if safe > self.emitted {
+            on_token(&self.text[self.emitted..safe]);
+            self.emitted = safe;
+        }
+        None
+    }
+
+    fn flush(&mut self, on_token: &mut impl FnMut(&str)) {
+        if self.text.len() > self.emitted {
+            on_token(&self.text[self.emitted..]);
+            self.emitted = self.text.len();
+        }
+    }
+}
+
+// samples from the logits left by the prompt eval until a stop condition, extending kv_tokens as tokens are evaluated
+// generated_before counts tokens of earlier branches, so the first token timeout only applies once
+// with a draft model every llama_eval call verifies the draft's proposals and can accept several tokens,
+// which needs the context to keep logits for every token it evaluates
+// guidance needs the negative logits for every sampled token, so the draft is not used with it
+#[allow(clippy::too_many_arguments)]
+fn sample_branch(
+    ctx: &mut LlamaContext,
+    kv_tokens: &mut Vec<llama_token>,
+    mut draft: Option<&mut DraftContext>,
+    mut guidance: Option<&mut Guidance>,
+    prompt_tokens: &[llama_token],
+    params: &GenerationParams,
+    n_threads: i32,
+    interrupt: &Interrupt,
+    generated_before: usize,
+    speculative: &mut SpeculativeTimings,
+    mut on_token: impl FnMut(&str),
+) -> Result<Branch, String> {
+    let n_ctx = unsafe { llama_n_ctx(ctx.as_ptr()) } as usize;
+    let eos = unsafe { llama_token_eos() };
+    let mut n_past = prompt_tokens.len();
+    let mut last_tokens = prompt_tokens.to_vec();
+    let mut sampled: Option<llama_token> = None;
+    let mut generated = 0;
+    let mut logprob = 0.0;
+    let mut n_eval = 0;
+    let mut eval_us = 0;
+    let mut sample_us = 0;
+    let mut mirostat_mu = 2.0 * params.sampling.mirostat_tau;
+    let mut output = Output::default();
+    let mut stop_word = None;
+    let finish_reason = 'generate: loop {
+        if generated >= params.max_tokens || n_past >= n_ctx {
+            break FinishReason::LENGTH;
+        }
+        // guidance evaluates the negative prompt and every token generated so far
+        if guidance
+            .as_deref()
+            .is_some_and(|guidance| guidance.negative_tokens.len() + generated > guidance.n_ctx())
+        {
+            break FinishReason::LENGTH;
+        }
+        if let Some(finish_reason) = interrupt.check(generated_before + generated) {
+            break finish_reason;
+        }
+        // tokens to evaluate, the last sampled one followed by what the draft proposes
+        let mut batch: Vec<llama_token> = sampled.into_iter().collect();
+        if let Some(draft) = draft
+            .as_deref_mut()
+            .filter(|_| sampled.is_some() && guidance.is_none())
+        {
+            let n = draft
+                .n_draft
+                .min(n_ctx - n_past - 1)
+                .min(params.max_tokens - generated);
+            if n > 0 {
+                let t_start = unsafe { llama_time_us() };
+                batch.extend(propose(draft, &last_tokens, n, n_threads)?);
+                speculative.add(0, 0, unsafe { llama_time_us() } - t_start);
+            }
+        }
+        if !batch.is_empty() {
+            let t_start = unsafe { llama_time_us() };
+            eval(ctx, &batch, n_past, n_threads)?;
+            eval_us += unsafe { llama_time_us() } - t_start;
+        }
+        // each row of logits is sampled for as long as the draft guessed the sampled token
+        let n_rows = batch.len().max(1);
+        let mut accepted = 0;
+        for row in 0..n_rows {
+            // guidance overwrites the logits, the logprob is taken from the model's own
+            let mut unguided = None;
+            if let Some(guidance) = guidance.as_deref_mut() {
+                unguided = Some(copy_logits(ctx, row));
+                guidance.apply(ctx, &last_tokens[prompt_tokens.len()..], n_threads)?;
+            }
+            let t_start = unsafe { llama_time_us() };
+            let token = sample(ctx, row, &params.sampling, &last_tokens, &mut mirostat_mu);
+            sample_us += unsafe { llama_time_us() } - t_start;
+            if row + 1 < n_rows && token == batch[row + 1] {
+                accepted += 1;
+            }
+            if row > 0 || !batch.is_empty() {
+                // the row's input token is now part of the sequence
+                kv_tokens.push(batch[row]);
+                n_past += 1;
+                n_eval += 1;
+            }
+            if token == eos {
+                speculative.add(n_rows - 1, accepted, 0);
+                break 'generate FinishReason::STOP;
+            }
+            logprob += match &unguided {
+                Some(logits) => logits_logprob(logits, token),
+                None => token_logprob(ctx, row, token),
+            } as f64;
+            sampled = Some(token);
+            generated += 1;
+            last_tokens.push(token);
+            stop_word = output.push(&token_to_bytes(ctx, token), &params.stop, &mut on_token);
+            if stop_word.is_some() {
+                speculative.add(n_rows - 1, accepted, 0);
+                break 'generate FinishReason::STOP;
+            }
+            if row + 1 >= n_rows || token != batch[row + 1] || generated >= params.max_tokens {
+                break;
+            }
+        }
+        if n_rows > 1 {
+            speculative.add(n_rows - 1, accepted, 0);
+        }
+    };
+    output.flush(&mut on_token);
+    Ok(Branch {
+        text: output.text,
+        sampled: last_tokens.split_off(prompt_tokens.len()),
+        finish_reason,
+        stop_word,
+        logprob,
+        n_eval,
+        eval_us,
+        sample_us,
+    })
+}
+
+// everything llama_eval left in the context, including the KV cache, logits and rng
+pub fn copy_state(ctx: &LlamaContext) -> Vec<u8> {
+    let mut state = vec![0u8; unsafe { llama_get_state_size(ctx.as_ptr()) }];
+    let n = unsafe { llama_copy_state_data(ctx.as_ptr(), state.as_mut_ptr()) };
+    state.truncate(n);
+    state
+}
+
+// runs a completion on the calling thread, on_token receives text as soon as it can no longer be part of a stop sequence
+// kv_tokens mirrors what is in the KV cache, so a prefix shared with the previous request is not evaluated again
+// with several choices the prompt is evaluated once and every branch starts from a snapshot of the context,
+// text is only streamed through on_token when there is a single choice
+#[allow(clippy::too_many_arguments)]
+pub fn generate(
+    ctx: &mut LlamaContext,
+    kv_tokens: &mut Vec<llama_token>,
+    mut draft: Option<&mut DraftContext>,
+    guidance_context: Option<&mut GuidanceContext>,
+    prompt_tokens: Vec<llama_token>,
+    params: &GenerationParams,
+    n_threads: i32,
+    interrupt: &Interrupt,
+    mut on_token: impl FnMut(&str),
+) -> Result<Completion, String> {
+    let n_ctx = unsafe { llama_n_ctx(ctx.as_ptr()) } as usize;
+    if params.n == 0 {
+        return Err("n must be at least 1".to_owned());
+    }
+    if params.best_of.is_some_and(|best_of| best_of < params.n) {
+        return Err("best_of must be at least n".to_owned());
+    }
+    let mut guidance = match (&params.negative_prompt, guidance_context) {
+        (Some(negative_prompt), Some(context)) if params.decoding == Decoding::SAMPLE => {
+            Some(Guidance {
+                context,
+                negative_tokens: tokenize_text(ctx, negative_prompt, true)?,
+                scale: params.cfg_scale,
+                guidance_us: 0,
+            })
+        }
+        (Some(_), None) if params.decoding == Decoding::SAMPLE => {
+            return Err(
+                "negative_prompt needs the model to be loaded with a guidance context".to_owned(),
+            )
+        }
+        _ => None,
+    };
+    if let Some(max_prompt_tokens) = params.max_prompt_tokens {
+        if prompt_tokens.len() > max_prompt_tokens {
+            return Err(format!(
+                "Prompt is too long ({} tokens, limit is {})",
+                prompt_tokens.len(),
+                max_prompt_tokens
+            ));
+        }
+    }
+    if prompt_tokens.len() + 4 > n_ctx {
+        return Err(format!(
+            "Prompt is too long ({} tokens, context size is {})",
+            prompt_tokens.len(),
+            n_ctx
+        ));
+    }
+    // the guidance context holds the negative prompt followed by the generated tokens
+    if let Some(guidance) = &guidance {
+        let n_negative = guidance.negative_tokens.len();
+        if let Some(max_prompt_tokens) = params.max_prompt_tokens {
+            if n_negative > max_prompt_tokens {
+                return Err(format!(
+                    "Negative prompt is too long ({} tokens, limit is {})",
+                    n_negative, max_prompt_tokens
+                ));
+            }
+        }
+        let n_ctx = guidance.n_ctx();
+        if n_negative + 4 > n_ctx {
+            return Err(format!(
+                "Negative prompt is too long ({} tokens, context size is {})",
+                n_negative, n_ctx
+            ));
+        }
+    }
+    // at least one token has to be evaluated to get logits to sample from
+    let n_cached = kv_tokens
+        .iter()
+        .zip(&prompt_tokens)
+        .take_while(|(cached, token)| cached == token)
+        .count()
+        .min(prompt_tokens.len().saturating_sub(1));
+    kv_tokens.truncate(n_cached);
+    let mut timings = Timings {
+        prompt_tokens: prompt_tokens.len(),
+        cached_tokens: n_cached,
+        ..Default::default()
+    };
+    if let Some(finish_reason) = interrupt.check(0) {
+        return Ok(Completion {
+            text: String::new(),
+            tokens: prompt_tokens,
+            n_past: n_cached,
+            seed: None,
+            finish_reason,
+            stop_word: None,
+            choices: Vec::new(),
+            timings,
+        });
+    }
+    let t_start = unsafe { llama_time_us() };
+    // with a draft model the context keeps logits for every token, sampling starts from the first row
+    // so the last prompt token is evaluated on its own
+    let split = if draft.is_some() {
+        prompt_tokens.len() - 1
+    } else {
+        prompt_tokens.len()
+    };
+    eval(ctx, &prompt_tokens[n_cached..split], n_cached, n_threads)?;
+    eval(ctx, &prompt_tokens[split..], split, n_threads)?;
+    let prompt_eval_us = unsafe { llama_time_us() } - t_start;
+    kv_tokens.extend_from_slice(&prompt_tokens[n_cached..]);
+    timings.prompt_eval_ms = prompt_eval_us as f64 / 1000.0;
+    timings.prompt_tokens_per_second = per_second(
+        timings.prompt_tokens - timings.cached_tokens,
+        prompt_eval_us,
+    );
+    if params.decoding == Decoding::BEAM {
+        let search = beam_search(ctx, kv_tokens, &prompt_tokens, params, n_threads, interrupt)?;
+        timings.generated_tokens = search.n_eval;
+        timings.eval_ms = search.eval_us as f64 / 1000.0;
+        timings.generated_tokens_per_second = per_second(search.n_eval, search.eval_us);
+        timings.sample_ms = search.expand_us as f64 / 1000.0;
+        let choices = if params.beam.return_beams {
+            search
+                .beams
+                .iter()
+                .map(|beam| Choice {
+                    message: beam.text.clone(),
+                    finish_reason: beam.finish_reason,
+                    seed: None,
+                    logprob: beam.logprob,
+                    score: Some(beam.score),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let best = search.beams.into_iter().next().ok_or("No beam finished")?;
+        let mut tokens = prompt_tokens;
+        tokens.extend(best.tokens);
+        return Ok(Completion {
+            text: best.text,
+            tokens,
+            n_past: kv_tokens.len(),
+            seed: None,
+            finish_reason: best.finish_reason,
+            stop_word: None,
+            choices,
+            timings,
+        });
+    }
+    let seed = set_seed(ctx, params.sampling.seed);
+    let n_choices = params.choices();
+    let snapshot = if n_choices > 1 {
+        Some(copy_state(ctx))
+    } else {
+        None
+    };
+    let mut branches: Vec<(i32, Branch)> = Vec::with_capacity(n_choices);
+    let mut speculative = SpeculativeTimings::default();
+    let mut generated = 0;
+    let (mut n_eval, mut eval_us, mut sample_us) = (0, 0, 0);
+    for i in 0..n_choices {
+        // each branch gets its own seed, derived from the request's so that it can be repeated
+        let branch_seed = seed.wrapping_add(i as i32) & i32::MAX;
+        if let Some(snapshot) = snapshot.as_ref().filter(|_| i > 0) {
+            // llama_set_state_data only reads from the buffer despite the mutable pointer
+            unsafe { llama_set_state_data(ctx.as_ptr(), snapshot.as_ptr() as *mut u8) };
+            kv_tokens.truncate(prompt_tokens.len());
+            set_seed(ctx, Some(branch_seed));
+        }
+        let branch = sample_branch(
+            ctx,
+            kv_tokens,
+            draft.as_deref_mut(),
+            guidance.as_mut(),
+            &prompt_tokens,
+            params,
+            n_threads,
+            interrupt,
+            generated,
+            &mut speculative,
+            |piece| {
+                if n_choices == 1 {
+                    on_token(piece)
+                }
+            },
+        )?;
+        generated += branch.sampled.len();
+        n_eval += branch.n_eval;
+        eval_us += branch.eval_us;
+        sample_us += branch.sample_us;
+        let interrupted = matches!(
+            branch.finish_reason,
+            FinishReason::CANCELLED | FinishReason::TIMEOUT
+        );
+        branches.push((branch_seed, branch));
+        if interrupted {
+            break;
+        }
+    }
+    // the KV cache holds the last branch, which is not necessarily the one returned
+    let n_past = kv_tokens.len();
+    timings.generated_tokens = generated;
+    timings.eval_ms = eval_us as f64 / 1000.0;
+    timings.generated_tokens_per_second = per_second(n_eval, eval_us);
+    timings.sample_ms = sample_us as f64 / 1000.0;
+    if draft.is_some() {
+        timings.speculative = Some(speculative);
+    }
+    timings.guidance_ms = guidance.map(|guidance| guidance.guidance_us as f64 / 1000.0);
+    if n_choices > 1 {
+        branches.sort_by(|(_, a), (_, b)| b.logprob.total_cmp(&a.logprob));
+        branches.truncate(params.n);
+    }
+    let choices = if n_choices > 1 {
+        branches
+            .iter()
+            .map(|(seed, branch)| Choice {
+                message: branch.text.clone(),
+                finish_reason: branch.finish_reason,
+                seed: Some(*seed),
+                logprob: branch.logprob,
+                score: None,
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let (seed, best) = branches.swap_remove(0);
+    let mut tokens = prompt_tokens;
+    tokens.extend(best.sampled);
+    Ok(Completion {
+        text: best.text,
+        tokens,
+        n_past,
+        seed: Some(seed),
+        finish_reason: best.finish_reason,
+        stop_word: best.stop_word,
+        choices,
+        timings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json::{from_value, json};
+
+    fn params(value: rocket::serde::json::Value) -> GenerationParams {
+        from_value(value).unwrap()
+    }
+
+    #[test]
+    fn clamp_lowers_to_limits() {
+        let limits = Limits {
+            max_tokens: Some(64),
+            timeout: Some(30.0),
+            max_n: Some(2),
+            max_best_of: Some(4),
+            max_beam_width: Some(3),
+            ..Limits::default()
+        };
+        let mut params = params(json!({
+            "max_tokens": 1000,
+            "timeout": 60.0,
+            "n": 3,
+            "best_of": 8,
+            "beam_width": 10,
+        }));
+        params.clamp(&limits).unwrap();
+        assert_eq!(params.max_tokens, 64);
+        assert_eq!(params.timeout, Some(30.0));
+        assert_eq!(params.n, 2);
+        assert_eq!(params.best_of, Some(4));
+        assert_eq!(params.beam.beam_width, 3);
+    }
+
+    #[test]
+    fn clamp_keeps_best_of_at_least_n() {
+        let limits = Limits {
+            max_n: Some(8),
+            max_best_of: Some(2),
+            ..Limits::default()
+        };
+        let mut params = params(json!({ "n": 5 }));
+        params.clamp(&limits).unwrap();
+        assert_eq!(params.n, 2);
+        assert!(params.choices() >= params.n);
+    }
+
+    #[test]
+    fn clamp_rejects_invalid_values() {
+        let limits = Limits::default();
+        assert!(params(json!({ "n": 0 })).clamp(&limits).is_err());
+        assert!(params(json!({ "n": 3, "best_of": 2 }))
+            .clamp(&limits)
+            .is_err());
+        assert!(params(json!({ "timeout": 1e300 })).clamp(&limits).is_err());
+        assert!(params(json!({ "negative_prompt": "a\0b" }))
+            .clamp(&limits)
+            .is_err());
+        assert!(params(json!({ "first_token_timeout": 1e300 }))
+            .clamp(&limits)
+            .is_err());
+    }
+
+    #[test]
+    fn partial_stop() {
+        let stop = vec!["</s>".to_owned(), "\n\n".to_owned()];
+        assert_eq!(partial_stop_len("hello", &stop), 0);
+        assert_eq!(partial_stop_len("hello<", &stop), 1);
+        assert_eq!(partial_stop_len("hello</", &stop), 2);
+        assert_eq!(partial_stop_len("hello\n", &stop), 1);
+        // a complete stop sequence is found by push, not held back here
+        assert_eq!(partial_stop_len("hello</s>", &stop), 0);
+        // never splits a multi-byte character of the stop sequence
+        assert_eq!(
+            partial_stop_len("a\u{e9}", &["\u{e9}t\u{e9}".to_owned()]),
+            2
+        );
+    }
+
+    fn push_all(
+        output: &mut Output,
+        pieces: &[&[u8]],
+        stop: &[String],
+    ) -> (String, Option<String>) {
+        let mut streamed = String::new();
+        for piece in pieces {
+            if let Some(stop) = output.push(piece, stop, &mut |text| streamed.push_str(text)) {
+                return (streamed, Some(stop));
+            }
+        }
+        output.flush(&mut |text| streamed.push_str(text));
+        (streamed, None)
+    }
+
+    #[test]
+    fn push_streams_text() {
+        let mut output = Output::default();
+        let (streamed, stop) = push_all(&mut output, &[b"Hello", b" world"], &[]);
+        assert_eq!(streamed, "Hello world");
+        assert_eq!(output.text, "Hello world");
+        assert_eq!(stop, None);
+    }
+
+    #[test]
+    fn push_stop_split_across_tokens() {
+        let stop = vec!["\nUser:".to_owned()];
+        let mut output = Output::default();
+        let (streamed, found) = push_all(
+            &mut output,
+            &[b"Hi there", b"\nUs", b"er", b": more"],
+            &stop,
+        );
+        assert_eq!(found, Some("\nUser:".to_owned()));
+        assert_eq!(output.text, "Hi there");
+        // the start of the stop sequence was held back rather than streamed
+        assert_eq!(streamed, "Hi there");
+    }
+
+    #[test]
+    fn push_releases_text_that_stops_matching() {
+        let stop = vec!["\nUser:".to_owned()];
+        let mut output = Output::default();
+        let (streamed, found) = push_all(&mut output, &[b"a\nUs", b"ual"], &stop);
+        assert_eq!(found, None);
+        assert_eq!(streamed, "a\nUsual");
+    }
+
+    #[test]
+    fn push_waits_for_multi_byte_characters() {
+        let mut output = Output::default();
+        let mut streamed = String::new();
+        let bytes = "\u{e9}".as_bytes();
+        output.push(&bytes[..1], &[], &mut |text| streamed.push_str(text));
+        assert_eq!(streamed, "");
+        output.push(&bytes[1..], &[], &mut |text| streamed.push_str(text));
+        assert_eq!(streamed, "\u{e9}");
+    }
+}
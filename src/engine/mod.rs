@@ -0,0 +1,262 @@
+pub mod beam;
+pub mod guidance;
+pub mod inference;
+pub mod models;
+pub mod speculative;
+
+use guidance::GuidanceContext;
+use libc::{c_char, c_int};
+use log::warn;
+use speculative::DraftContext;
+use std::{
+    ffi::CString,
+    mem::size_of,
+    path::{Path, PathBuf},
+    ptr::NonNull,
+};
+
+use crate::{
+    config::ModelParams,
+    sys::{
+        llama_apply_lora_from_file, llama_context, llama_context_default_params,
+        llama_context_params, llama_free, llama_init_backend, llama_init_from_file,
+        llama_load_session_file, llama_n_ctx, llama_n_vocab, llama_save_session_file, llama_token,
+        llama_tokenize,
+    },
+};
+
+// a context created by load_model, freed when dropped
+// functions that evaluate or sample take it mutably, so logits read from it cannot change underneath
+pub struct LlamaContext(NonNull<llama_context>);
+
+// llama.cpp contexts can move between threads, just not be used from two at once
+unsafe impl Send for LlamaContext {}
+
+impl LlamaContext {
+    // valid for as long as self is
+    pub fn as_ptr(&self) -> *mut llama_context {
+        self.0.as_ptr()
+    }
+}
+
+impl Drop for LlamaContext {
+    fn drop(&mut self) {
+        unsafe { llama_free(self.0.as_ptr()) };
+    }
+}
+
+// the contexts of the loaded model, shared by every request that uses it
+pub struct ModelContext {
+    pub ctx: Option<LlamaContext>,
+    // the tokens currently held in ctx's KV cache
+    pub kv_tokens: Vec<llama_token>,
+    pub draft: Option<DraftContext>,
+    pub guidance: Option<GuidanceContext>,
+}
+
+impl ModelContext {
+    // drops the contexts, queued completions then see the context is gone
+    pub fn free(&mut self) {
+        self.ctx = None;
+        self.kv_tokens.clear();
+        self.draft = None;
+        self.guidance = None;
+    }
+}
+
+// once per process, before any model is loaded
+pub fn init() {
+    unsafe { llama_init_backend() };
+}
+
+pub fn load_model(
+    path_to_model: &str,
+    model_params: &ModelParams,
+    lora_adapters: &[PathBuf],
+    lora_base: Option<&Path>,
+    n_threads: i32,
+) -> Result<(LlamaContext, llama_context_params), String> {
+    let mut params = unsafe { llama_context_default_params() };
+    params.n_ctx = model_params.n_ctx.unwrap_or(params.n_ctx);
+    params.n_gpu_layers = model_params.n_gpu_layers.unwrap_or(params.n_gpu_layers);
+    params.seed = model_params.seed.unwrap_or(params.seed);
+    params.f16_kv = model_params.f16_kv.unwrap_or(params.f16_kv);
+    params.use_mmap = model_params.use_mmap.unwrap_or(params.use_mmap);
+    params.use_mlock = model_params.use_mlock.unwrap_or(params.use_mlock);
+    params.vocab_only = model_params.vocab_only.unwrap_or(params.vocab_only);
+    params.embedding = model_params.embedding.unwrap_or(params.embedding);
+    // the draft's proposals are verified in one llama_eval call, which needs the logits of every token
+    params.logits_all = model_params.draft_model.is_some();
+    if !lora_adapters.is_empty() {
+        // adapters are applied to the weights in place, which a read-only mapping does not allow
+        params.use_mmap = false;
+    }
+    let ctx = unsafe {
+        llama_init_from_file(
+            CString::new(path_to_model)
+                .expect("Path contains invalid characters")
+                .as_c_str()
+                .as_ptr(),
+            params,
+        )
+    };
+    let ctx = LlamaContext(NonNull::new(ctx).ok_or("Unable to load model")?);
+    let lora_base = lora_base.map(|base| {
+        CString::new(base.to_str().unwrap()).expect("Path contains invalid characters")
+    });
+    for lora_adapter in lora_adapters {
+        let res = unsafe {
+            llama_apply_lora_from_file(
+                ctx.as_ptr(),
+                CString::new(lora_adapter.to_str().unwrap())
+                    .expect("Path contains invalid characters")
+                    .as_c_str()
+                    .as_ptr(),
+                lora_base
+                    .as_ref()
+                    .map_or(std::ptr::null(), |base| base.as_ptr()),
+                n_threads,
+            )
+        };
+        if res != 0 {
+            return Err(format!(
+                "Unable to apply LoRA adapter {}",
+                lora_adapter.display()
+            ));
+        }
+    }
+    Ok((ctx, params))
+}
+
+// loaded with the target's context parameters, adapters are only applied to the target
+pub fn load_draft(
+    path: &Path,
+    model_params: &ModelParams,
+    target: &LlamaContext,
+    n_threads: i32,
+) -> Result<DraftContext, String> {
+    let draft_params = ModelParams {
+        lora_adapters: None,
+        lora_base: None,
+        draft_model: None,
+        guidance: None,
+        embedding: None,
+        ..model_params.clone()
+    };
+    let (ctx, _) = load_model(path.to_str().unwrap(), &draft_params, &[], None, n_threads)
+        .map_err(|_| "Unable to load draft model".to_owned())?;
+    if unsafe { llama_n_vocab(ctx.as_ptr()) != llama_n_vocab(target.as_ptr()) } {
+        return Err("Draft model has a different vocabulary".to_owned());
+    }
+    Ok(DraftContext {
+        ctx,
+        kv_tokens: Vec::new(),
+        n_draft: model_params
+            .draft_tokens
+            .unwrap_or(speculative::DEFAULT_DRAFT_TOKENS)
+            .max(1),
+    })
+}
+
+// a saved KV cache, restored when it covers more of the prompt than what the context holds
+pub struct Session {
+    pub path: PathBuf,
+    pub n_tokens: usize,
+}
+
+fn load_session(ctx: &mut LlamaContext, path: &Path) -> Result<Vec<llama_token>, ()> {
+    let n_ctx = unsafe { llama_n_ctx(ctx.as_ptr()) } as usize;
+    let mut res: Vec<llama_token> = Vec::with_capacity(n_ctx);
+    let mut n: usize = 0;
+    let loaded = unsafe {
+        llama_load_session_file(
+            ctx.as_ptr(),
+            CString::new(path.to_str().unwrap())
+                .expect("Path contains invalid characters")
+                .as_c_str()
+                .as_ptr(),
+            res.as_mut_ptr(),
+            n_ctx,
+            &mut n,
+        )
+    };
+    if !loaded {
+        return Err(());
+    }
+    unsafe { res.set_len(n) };
+    Ok(res)
+}
+
+pub fn save_session(ctx: &LlamaContext, path: &Path, tokens: &[llama_token]) -> Result<(), String> {
+    let saved = unsafe {
+        llama_save_session_file(
+            ctx.as_ptr(),
+            CString::new(path.to_str().unwrap())
+                .expect("Path contains invalid characters")
+                .as_c_str()
+                .as_ptr(),
+            tokens.as_ptr(),
+            tokens.len(),
+        )
+    };
+    if saved {
+        Ok(())
+    } else {
+        Err(format!("Unable to save session to {}", path.display()))
+    }
+}
+
+pub fn restore_session(
+    ctx: &mut LlamaContext,
+    kv_tokens: &mut Vec<llama_token>,
+    session: &Session,
+    prompt_tokens: &[llama_token],
+) {
+    let n_cached = kv_tokens
+        .iter()
+        .zip(prompt_tokens)
+        .take_while(|(cached, token)| cached == token)
+        .count();
+    if n_cached >= session.n_tokens || !session.path.exists() {
+        return;
+    }
+    match load_session(ctx, &session.path) {
+        Ok(tokens) => *kv_tokens = tokens,
+        Err(_) => {
+            // the state may have been partly overwritten
+            kv_tokens.clear();
+            warn!("Unable to load session file {}", session.path.display());
+        }
+    }
+}
+
+pub fn tokenize_text(
+    ctx: &LlamaContext,
+    text: &str,
+    add_beginning_of_sentence_token: bool,
+) -> Result<Vec<llama_token>, String> {
+    // JSON strings can hold a null character, C strings cannot
+    let text_c = CString::new(text).map_err(|_| "Text contains a null character".to_owned())?;
+    let mut res: Vec<llama_token> = Vec::new();
+    let n = text.len()
+        + (if add_beginning_of_sentence_token {
+            size_of::<c_char>()
+        } else {
+            0
+        });
+    res.reserve(n);
+    let n = unsafe {
+        llama_tokenize(
+            ctx.as_ptr(),
+            text_c.as_ptr(),
+            res.as_mut_ptr(),
+            n as c_int,
+            add_beginning_of_sentence_token,
+        )
+    };
+    if n < 0 {
+        return Err("Unable to tokenize text".to_owned());
+    }
+    unsafe { res.set_len(n as usize) };
+    Ok(res)
+}
@@ -0,0 +1,529 @@
+use log::{error, warn};
+use notify::{recommended_watcher, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_dir, read_to_string, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    engine::LlamaContext,
+    sys::{
+        llama_context_params, llama_ftype, llama_ftype_LLAMA_FTYPE_ALL_F32,
+        llama_ftype_LLAMA_FTYPE_MOSTLY_F16, llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_0,
+        llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_1, llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_1_SOME_F16,
+        llama_ftype_LLAMA_FTYPE_MOSTLY_Q5_0, llama_ftype_LLAMA_FTYPE_MOSTLY_Q5_1,
+        llama_ftype_LLAMA_FTYPE_MOSTLY_Q8_0, llama_get_state_size, llama_n_ctx, llama_n_embd,
+        llama_n_vocab, LLAMA_FILE_MAGIC_GGJT, LLAMA_FILE_MAGIC_GGMF, LLAMA_FILE_MAGIC_GGML,
+    },
+};
+
+// guards against symlink loops
+const MAX_DEPTH: usize = 8;
+
+// model metadata read from the file header, without loading the weights
+#[derive(Clone, Serialize)]
+pub struct ModelInfo {
+    // path relative to the model directory
+    pub name: String,
+    pub size: u64,
+    pub format: &'static str,
+    pub version: u32,
+    pub ftype: &'static str,
+    pub n_vocab: u32,
+    pub n_embd: u32,
+    pub n_mult: u32,
+    pub n_head: u32,
+    pub n_layer: u32,
+}
+
+impl ModelInfo {
+    // weights in the file, from the shapes of the llama architecture
+    pub fn n_params(&self) -> u64 {
+        let n_vocab = self.n_vocab as u64;
+        let n_embd = self.n_embd as u64;
+        let n_mult = self.n_mult.max(1) as u64;
+        // feed forward width, rounded up the way llama.cpp does
+        let n_ff = (2 * (4 * n_embd) / 3).div_ceil(n_mult) * n_mult;
+        // embeddings and output, then attention, feed forward and norms in every layer
+        2 * n_vocab * n_embd
+            + self.n_layer as u64 * (4 * n_embd * n_embd + 3 * n_embd * n_ff + 2 * n_embd)
+            + n_embd
+    }
+}
+
+const FTYPE_NAMES: [(llama_ftype, &str); 8] = [
+    (llama_ftype_LLAMA_FTYPE_ALL_F32, "all_f32"),
+    (llama_ftype_LLAMA_FTYPE_MOSTLY_F16, "mostly_f16"),
+    (llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_0, "mostly_q4_0"),
+    (llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_1, "mostly_q4_1"),
+    (
+        llama_ftype_LLAMA_FTYPE_MOSTLY_Q4_1_SOME_F16,
+        "mostly_q4_1_some_f16",
+    ),
+    (llama_ftype_LLAMA_FTYPE_MOSTLY_Q8_0, "mostly_q8_0"),
+    (llama_ftype_LLAMA_FTYPE_MOSTLY_Q5_0, "mostly_q5_0"),
+    (llama_ftype_LLAMA_FTYPE_MOSTLY_Q5_1, "mostly_q5_1"),
+];
+
+fn ftype_name(ftype: llama_ftype) -> &'static str {
+    FTYPE_NAMES
+        .iter()
+        .find(|(t, _)| *t == ftype)
+        .map(|(_, name)| *name)
+        .unwrap_or("unknown")
+}
+
+// returns None for anything that is not a ggml, ggmf or ggjt model
+pub fn read_model_header(path: &Path) -> Option<ModelInfo> {
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut read_u32 = || -> Option<u32> {
+        let mut buf = [0u8; 4];
+        file.read_exact(&mut buf).ok()?;
+        Some(u32::from_le_bytes(buf))
+    };
+    let (format, version) = match read_u32()? {
+        LLAMA_FILE_MAGIC_GGML => ("ggml", 0),
+        LLAMA_FILE_MAGIC_GGMF => ("ggmf", read_u32()?),
+        LLAMA_FILE_MAGIC_GGJT => ("ggjt", read_u32()?),
+        _ => return None,
+    };
+    let n_vocab = read_u32()?;
+    let n_embd = read_u32()?;
+    let n_mult = read_u32()?;
+    let n_head = read_u32()?;
+    let n_layer = read_u32()?;
+    let _n_rot = read_u32()?;
+    let ftype = read_u32()?;
+    Some(ModelInfo {
+        name: String::new(),
+        size,
+        format,
+        version,
+        ftype: ftype_name(ftype),
+        n_vocab,
+        n_embd,
+        n_mult,
+        n_head,
+        n_layer,
+    })
+}
+
+fn scan_dir(root: &Path, dir: &Path, depth: usize, res: &mut Vec<ModelInfo>) {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Unable to read {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                error!("Error reading file in model directory: {}", e);
+                continue;
+            }
+        };
+        if path.is_dir() {
+            if depth < MAX_DEPTH {
+                scan_dir(root, &path, depth + 1, res);
+            }
+        } else if let Some(mut info) = read_model_header(&path) {
+            if let Some(name) = path.strip_prefix(root).ok().and_then(|p| p.to_str()) {
+                info.name = name.to_owned();
+                res.push(info);
+            }
+        }
+    }
+}
+
+pub fn read_model_dir(model_dir: &Path) -> Vec<ModelInfo> {
+    let mut res: Vec<ModelInfo> = Vec::new();
+    scan_dir(model_dir, model_dir, 0, &mut res);
+    res.sort_by(|a, b| a.name.cmp(&b.name));
+    res
+}
+
+// cached listing of the model directory, kept up to date by a filesystem watcher
+pub struct ModelDirectory {
+    path: PathBuf,
+    models: Arc<RwLock<Vec<ModelInfo>>>,
+    _watcher: Option<RecommendedWatcher>,
+}
+
+impl ModelDirectory {
+    pub fn new(path: PathBuf) -> ModelDirectory {
+        let models = Arc::new(RwLock::new(read_model_dir(&path)));
+        let watched_models = models.clone();
+        let watched_path = path.clone();
+        let watcher = recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                *watched_models.write().unwrap() = read_model_dir(&watched_path);
+            }
+            Ok(_) => {}
+            Err(e) => error!("Error watching model directory: {}", e),
+        })
+        .and_then(|mut watcher| {
+            watcher.watch(&path, RecursiveMode::Recursive)?;
+            Ok(watcher)
+        });
+        let watcher = match watcher {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!(
+                    "Unable to watch model directory, changes will be picked up on load: {}",
+                    e
+                );
+                None
+            }
+        };
+        ModelDirectory {
+            path,
+            models,
+            _watcher: watcher,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ModelInfo> {
+        self.models.read().unwrap().clone()
+    }
+
+    // rescans when the name is unknown in case the watcher missed a change
+    pub fn find(&self, name: &str) -> Option<ModelInfo> {
+        let found = self.list().into_iter().find(|model| model.name == name);
+        if found.is_some() {
+            return found;
+        }
+        let models = read_model_dir(&self.path);
+        let found = models.iter().find(|model| model.name == name).cloned();
+        *self.models.write().unwrap() = models;
+        found
+    }
+
+    // resolves a file name from a request, refusing anything outside the model directory
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+        let path = self.path.join(relative);
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+}
+
+// seconds or a duration such as "10m", negative keeps the model loaded until it is unloaded
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum KeepAlive {
+    SECONDS(f64),
+    DURATION(String),
+}
+
+impl KeepAlive {
+    // none keeps the model loaded
+    pub fn duration(&self) -> Result<Option<Duration>, String> {
+        let invalid = || format!("Invalid keep_alive: {}", self);
+        let seconds = match self {
+            KeepAlive::SECONDS(seconds) => *seconds,
+            KeepAlive::DURATION(text) => {
+                let text = text.trim();
+                let (number, unit) =
+                    text.split_at(text.find(char::is_alphabetic).unwrap_or(text.len()));
+                let unit = match unit {
+                    "ms" => 0.001,
+                    "" | "s" => 1.0,
+                    "m" => 60.0,
+                    "h" => 3600.0,
+                    _ => return Err(invalid()),
+                };
+                number.parse::<f64>().map_err(|_| invalid())? * unit
+            }
+        };
+        if seconds < 0.0 {
+            return Ok(None);
+        }
+        Duration::try_from_secs_f64(seconds)
+            .map(Some)
+            .map_err(|_| invalid())
+    }
+}
+
+impl std::fmt::Display for KeepAlive {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeepAlive::SECONDS(seconds) => write!(f, "{}", seconds),
+            KeepAlive::DURATION(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+// effective llama_context_params of the loaded context
+#[derive(Clone, Serialize)]
+pub struct ContextParams {
+    pub n_ctx: i32,
+    pub n_gpu_layers: i32,
+    pub seed: i32,
+    pub f16_kv: bool,
+    pub use_mmap: bool,
+    pub use_mlock: bool,
+    pub vocab_only: bool,
+    pub embedding: bool,
+}
+
+impl From<&llama_context_params> for ContextParams {
+    fn from(params: &llama_context_params) -> Self {
+        ContextParams {
+            n_ctx: params.n_ctx,
+            n_gpu_layers: params.n_gpu_layers,
+            seed: params.seed,
+            f16_kv: params.f16_kv,
+            use_mmap: params.use_mmap,
+            use_mlock: params.use_mlock,
+            vocab_only: params.vocab_only,
+            embedding: params.embedding,
+        }
+    }
+}
+
+// the compute backend selected by cargo features when the server was built,
+// an external llama library was built with whichever backend it was configured with
+pub const BACKEND: &str = if option_env!("LLAMA_EXTERNAL_LIBRARY").is_some() {
+    "external"
+} else if cfg!(feature = "cublas") {
+    "cublas"
+} else if cfg!(feature = "clblast") {
+    "clblast"
+} else if cfg!(feature = "openblas") {
+    "openblas"
+} else if cfg!(feature = "blis") {
+    "blis"
+} else {
+    "cpu"
+};
+
+// what the state in a session file depends on, llama_set_state_data aborts when the sizes of
+// the logits, the embedding or the KV cache differ from those of the context it was saved from
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionParams {
+    pub model: String,
+    pub n_ctx: i32,
+    pub f16_kv: bool,
+    pub logits_all: bool,
+    pub embedding: bool,
+}
+
+// what was actually loaded, as opposed to what the file header says
+#[derive(Clone, Serialize)]
+pub struct LoadedModel {
+    pub name: String,
+    pub n_vocab: i32,
+    pub n_ctx: i32,
+    pub n_embd: i32,
+    pub params: ContextParams,
+    // in the order they were applied, relative to the model directory
+    pub lora_adapters: Vec<String>,
+    pub lora_base: Option<String>,
+    // speculative decoding, relative to the model directory
+    pub draft_model: Option<String>,
+    pub draft_tokens: Option<usize>,
+    // whether negative prompts can be used
+    pub guidance: bool,
+    // bytes needed to snapshot the context, mostly the KV cache
+    pub state_size: usize,
+    pub model_size: u64,
+    // resident set of the whole process, refreshed on every request
+    pub resident_memory: Option<u64>,
+    // seconds the model stays loaded once idle, none keeps it loaded
+    pub keep_alive: Option<f64>,
+    pub backend: &'static str,
+}
+
+impl LoadedModel {
+    pub fn new(
+        ctx: &LlamaContext,
+        model: &ModelInfo,
+        params: &llama_context_params,
+        lora_adapters: Vec<String>,
+        lora_base: Option<String>,
+    ) -> LoadedModel {
+        unsafe {
+            LoadedModel {
+                name: model.name.clone(),
+                n_vocab: llama_n_vocab(ctx.as_ptr()),
+                n_ctx: llama_n_ctx(ctx.as_ptr()),
+                n_embd: llama_n_embd(ctx.as_ptr()),
+                params: ContextParams::from(params),
+                lora_adapters,
+                lora_base,
+                draft_model: None,
+                draft_tokens: None,
+                guidance: false,
+                state_size: llama_get_state_size(ctx.as_ptr()),
+                model_size: model.size,
+                resident_memory: resident_memory(),
+                keep_alive: None,
+                backend: BACKEND,
+            }
+        }
+    }
+
+    pub fn session_params(&self) -> SessionParams {
+        SessionParams {
+            model: self.name.clone(),
+            n_ctx: self.n_ctx,
+            f16_kv: self.params.f16_kv,
+            // set for the draft model's verification, see load_model
+            logits_all: self.draft_model.is_some(),
+            embedding: self.params.embedding,
+        }
+    }
+}
+
+// only available where /proc is
+pub fn resident_memory() -> Option<u64> {
+    let statm = read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size > 0 {
+        Some(pages * page_size as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keep_alive(text: &str) -> Result<Option<Duration>, String> {
+        KeepAlive::DURATION(text.to_owned()).duration()
+    }
+
+    #[test]
+    fn keep_alive_durations() {
+        assert_eq!(keep_alive("0"), Ok(Some(Duration::ZERO)));
+        assert_eq!(keep_alive("-1"), Ok(None));
+        assert_eq!(keep_alive("5m"), Ok(Some(Duration::from_secs(300))));
+        assert_eq!(keep_alive(" 1h "), Ok(Some(Duration::from_secs(3600))));
+        assert_eq!(keep_alive("250ms"), Ok(Some(Duration::from_millis(250))));
+        assert_eq!(KeepAlive::SECONDS(-1.0).duration(), Ok(None));
+        assert_eq!(
+            KeepAlive::SECONDS(30.0).duration(),
+            Ok(Some(Duration::from_secs(30)))
+        );
+    }
+
+    #[test]
+    fn keep_alive_invalid() {
+        assert!(keep_alive("5d").is_err());
+        assert!(keep_alive("m").is_err());
+        assert!(keep_alive("").is_err());
+        assert!(KeepAlive::SECONDS(f64::NAN).duration().is_err());
+        assert!(KeepAlive::SECONDS(f64::INFINITY).duration().is_err());
+    }
+
+    #[test]
+    fn keep_alive_from_json() {
+        let seconds: KeepAlive = rocket::serde::json::from_str("0").unwrap();
+        assert_eq!(seconds.duration(), Ok(Some(Duration::ZERO)));
+        let text: KeepAlive = rocket::serde::json::from_str("\"5m\"").unwrap();
+        assert_eq!(text.duration(), Ok(Some(Duration::from_secs(300))));
+    }
+
+    // writes the header to a file of its own and reads it back
+    fn header(name: &str, words: &[u32], extra: &[u8]) -> Option<ModelInfo> {
+        let path = std::env::temp_dir().join(format!(
+            "llama-rust-server-{}-{}.bin",
+            std::process::id(),
+            name
+        ));
+        let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        bytes.extend(extra);
+        std::fs::write(&path, bytes).unwrap();
+        let info = read_model_header(&path);
+        std::fs::remove_file(&path).unwrap();
+        info
+    }
+
+    // n_vocab, n_embd, n_mult, n_head, n_layer, n_rot and ftype
+    const HPARAMS: [u32; 7] = [32000, 4096, 256, 32, 32, 128, 2];
+
+    #[test]
+    fn reads_ggjt_header() {
+        let mut words = vec![LLAMA_FILE_MAGIC_GGJT, 1];
+        words.extend(HPARAMS);
+        let info = header("ggjt", &words, &[0; 12]).unwrap();
+        assert_eq!(info.format, "ggjt");
+        assert_eq!(info.version, 1);
+        assert_eq!(info.size, 48);
+        assert_eq!(info.ftype, "mostly_q4_0");
+        assert_eq!(
+            (
+                info.n_vocab,
+                info.n_embd,
+                info.n_mult,
+                info.n_head,
+                info.n_layer
+            ),
+            (32000, 4096, 256, 32, 32)
+        );
+    }
+
+    #[test]
+    fn ggml_header_has_no_version() {
+        let mut words = vec![LLAMA_FILE_MAGIC_GGML];
+        words.extend(HPARAMS);
+        let info = header("ggml", &words, &[]).unwrap();
+        assert_eq!(info.format, "ggml");
+        assert_eq!(info.version, 0);
+        assert_eq!(info.n_vocab, 32000);
+    }
+
+    #[test]
+    fn unknown_ftype() {
+        let mut words = vec![LLAMA_FILE_MAGIC_GGMF, 1];
+        words.extend(&HPARAMS[..6]);
+        words.push(99);
+        assert_eq!(header("ftype", &words, &[]).unwrap().ftype, "unknown");
+    }
+
+    #[test]
+    fn truncated_header() {
+        let mut words = vec![LLAMA_FILE_MAGIC_GGJT, 1];
+        words.extend(HPARAMS);
+        for len in 0..words.len() {
+            assert!(
+                header("truncated", &words[..len], &[]).is_none(),
+                "{} words",
+                len
+            );
+        }
+        // a partial word at the end
+        assert!(header("partial", &words[..8], &[0, 0]).is_none());
+    }
+
+    #[test]
+    fn unknown_magic() {
+        let mut words = vec![0x46554747, 3];
+        words.extend(HPARAMS);
+        assert!(header("gguf", &words, &[]).is_none());
+        assert!(header("text", &[], b"not a model at all, just some text").is_none());
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(read_model_header(Path::new("/nonexistent/model.bin")).is_none());
+    }
+}
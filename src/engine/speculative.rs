@@ -0,0 +1,74 @@
+use serde::Serialize;
+use std::slice;
+
+use crate::{
+    engine::{
+        inference::{catch_up, eval},
+        LlamaContext,
+    },
+    sys::{llama_get_logits, llama_n_vocab, llama_token, llama_token_eos},
+};
+
+// tokens proposed per step when the model parameters do not say
+pub const DEFAULT_DRAFT_TOKENS: usize = 4;
+
+// a smaller model with the same vocabulary that proposes tokens for the loaded model to verify
+pub struct DraftContext {
+    pub ctx: LlamaContext,
+    // tokens in the draft's KV cache, like kv_tokens of ModelContext
+    pub kv_tokens: Vec<llama_token>,
+    pub n_draft: usize,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct SpeculativeTimings {
+    pub draft_ms: f64,
+    pub drafted_tokens: usize,
+    pub accepted_tokens: usize,
+    pub acceptance_rate: f64,
+}
+
+impl SpeculativeTimings {
+    pub fn add(&mut self, drafted: usize, accepted: usize, draft_us: i64) {
+        self.drafted_tokens += drafted;
+        self.accepted_tokens += accepted;
+        self.draft_ms += draft_us as f64 / 1000.0;
+        if self.drafted_tokens > 0 {
+            self.acceptance_rate = self.accepted_tokens as f64 / self.drafted_tokens as f64;
+        }
+    }
+}
+
+fn argmax(ctx: &LlamaContext) -> llama_token {
+    let n_vocab = unsafe { llama_n_vocab(ctx.as_ptr()) } as usize;
+    let logits = unsafe { slice::from_raw_parts(llama_get_logits(ctx.as_ptr()), n_vocab) };
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map_or(0, |(id, _)| id as llama_token)
+}
+
+// greedily proposes up to n tokens to follow tokens, after bringing the draft's KV cache up to date with them
+pub fn propose(
+    draft: &mut DraftContext,
+    tokens: &[llama_token],
+    n: usize,
+    n_threads: i32,
+) -> Result<Vec<llama_token>, String> {
+    catch_up(&mut draft.ctx, &mut draft.kv_tokens, tokens, n_threads)?;
+    let mut proposed = Vec::with_capacity(n);
+    while proposed.len() < n {
+        if let Some(&token) = proposed.last() {
+            eval(&mut draft.ctx, &[token], draft.kv_tokens.len(), n_threads)?;
+            draft.kv_tokens.push(token);
+        }
+        let token = argmax(&draft.ctx);
+        proposed.push(token);
+        // nothing follows the end of text
+        if token == unsafe { llama_token_eos() } {
+            break;
+        }
+    }
+    Ok(proposed)
+}
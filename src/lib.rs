@@ -0,0 +1,17 @@
+// the inference engine behind the server and its HTTP API, other services can embed the engine directly
+
+pub mod api;
+pub mod config;
+pub mod engine;
+pub mod sampling;
+
+// the llama.cpp bindings generated by build.rs
+#[allow(
+    non_camel_case_types,
+    non_upper_case_globals,
+    non_snake_case,
+    dead_code
+)]
+pub mod sys {
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+}
@@ -0,0 +1,183 @@
+use serde::Deserialize;
+use std::slice;
+use uuid::Uuid;
+
+use crate::{
+    engine::LlamaContext,
+    sys::{
+        llama_get_logits, llama_n_vocab, llama_sample_frequency_and_presence_penalties,
+        llama_sample_repetition_penalty, llama_sample_tail_free, llama_sample_temperature,
+        llama_sample_token, llama_sample_token_greedy, llama_sample_token_mirostat,
+        llama_sample_token_mirostat_v2, llama_sample_top_k, llama_sample_top_p,
+        llama_sample_typical, llama_set_rng_seed, llama_token, llama_token_data,
+        llama_token_data_array,
+    },
+};
+
+// defaults follow llama.cpp's main example
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SamplingParams {
+    // 0 or less samples greedily
+    pub temperature: f32,
+    // 0 or less uses the whole vocabulary
+    pub top_k: i32,
+    pub top_p: f32,
+    pub tfs_z: f32,
+    pub typical_p: f32,
+    pub repeat_penalty: f32,
+    // tokens considered for penalties, -1 for the whole context
+    pub repeat_last_n: i32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    // 0 disabled, 1 mirostat, 2 mirostat 2.0
+    pub mirostat: i32,
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    // reseeds the context's rng for this request, missing or negative picks a random seed
+    pub seed: Option<i32>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        SamplingParams {
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.95,
+            tfs_z: 1.0,
+            typical_p: 1.0,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            mirostat: 0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            seed: None,
+        }
+    }
+}
+
+// returns the seed actually used so the request can be repeated
+pub fn set_seed(ctx: &mut LlamaContext, seed: Option<i32>) -> i32 {
+    let seed = match seed {
+        Some(seed) if seed >= 0 => seed,
+        // llama_set_rng_seed would fall back to the time, which repeats within a second
+        _ => (Uuid::new_v4().as_u128() as u32 >> 1) as i32,
+    };
+    unsafe { llama_set_rng_seed(ctx.as_ptr(), seed) };
+    seed
+}
+
+// logits after the row-th token of the last llama_eval call, only the first row exists without logits_all
+fn logits(ctx: &LlamaContext, row: usize) -> &[f32] {
+    let n_vocab = unsafe { llama_n_vocab(ctx.as_ptr()) } as usize;
+    unsafe { slice::from_raw_parts(llama_get_logits(ctx.as_ptr()).add(row * n_vocab), n_vocab) }
+}
+
+// log probability of token under the raw logits
+pub fn token_logprob(ctx: &LlamaContext, row: usize, token: llama_token) -> f32 {
+    logits_logprob(logits(ctx, row), token)
+}
+
+// a row of logits as they are now, before something overwrites them in place
+pub fn copy_logits(ctx: &LlamaContext, row: usize) -> Vec<f32> {
+    logits(ctx, row).to_vec()
+}
+
+pub fn logits_logprob(logits: &[f32], token: llama_token) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|logit| (logit - max).exp()).sum();
+    logits[token as usize] - max - sum.ln()
+}
+
+// candidates for the next token from a row of logits
+pub fn candidates(ctx: &LlamaContext, row: usize) -> Vec<llama_token_data> {
+    logits(ctx, row)
+        .iter()
+        .enumerate()
+        .map(|(id, &logit)| llama_token_data {
+            id: id as llama_token,
+            logit,
+            p: 0.0,
+        })
+        .collect()
+}
+
+pub fn sample(
+    ctx: &mut LlamaContext,
+    row: usize,
+    params: &SamplingParams,
+    last_tokens: &[llama_token],
+    mirostat_mu: &mut f32,
+) -> llama_token {
+    let mut candidates = candidates(ctx, row);
+    let n_vocab = candidates.len();
+    let mut candidates_p = llama_token_data_array {
+        data: candidates.as_mut_ptr(),
+        size: candidates.len(),
+        sorted: false,
+    };
+    let repeat_last_n = if params.repeat_last_n < 0 {
+        last_tokens.len()
+    } else {
+        (params.repeat_last_n as usize).min(last_tokens.len())
+    };
+    let penalty_tokens = &last_tokens[last_tokens.len() - repeat_last_n..];
+    unsafe {
+        llama_sample_repetition_penalty(
+            ctx.as_ptr(),
+            &mut candidates_p,
+            penalty_tokens.as_ptr(),
+            penalty_tokens.len(),
+            params.repeat_penalty,
+        );
+        llama_sample_frequency_and_presence_penalties(
+            ctx.as_ptr(),
+            &mut candidates_p,
+            penalty_tokens.as_ptr(),
+            penalty_tokens.len(),
+            params.frequency_penalty,
+            params.presence_penalty,
+        );
+        if params.temperature <= 0.0 {
+            return llama_sample_token_greedy(ctx.as_ptr(), &mut candidates_p);
+        }
+        match params.mirostat {
+            1 => {
+                llama_sample_temperature(ctx.as_ptr(), &mut candidates_p, params.temperature);
+                llama_sample_token_mirostat(
+                    ctx.as_ptr(),
+                    &mut candidates_p,
+                    params.mirostat_tau,
+                    params.mirostat_eta,
+                    100,
+                    mirostat_mu,
+                )
+            }
+            2 => {
+                llama_sample_temperature(ctx.as_ptr(), &mut candidates_p, params.temperature);
+                llama_sample_token_mirostat_v2(
+                    ctx.as_ptr(),
+                    &mut candidates_p,
+                    params.mirostat_tau,
+                    params.mirostat_eta,
+                    mirostat_mu,
+                )
+            }
+            _ => {
+                let top_k = if params.top_k <= 0 {
+                    n_vocab as i32
+                } else {
+                    params.top_k
+                };
+                llama_sample_top_k(ctx.as_ptr(), &mut candidates_p, top_k, 1);
+                llama_sample_tail_free(ctx.as_ptr(), &mut candidates_p, params.tfs_z, 1);
+                llama_sample_typical(ctx.as_ptr(), &mut candidates_p, params.typical_p, 1);
+                llama_sample_top_p(ctx.as_ptr(), &mut candidates_p, params.top_p, 1);
+                llama_sample_temperature(ctx.as_ptr(), &mut candidates_p, params.temperature);
+                llama_sample_token(ctx.as_ptr(), &mut candidates_p)
+            }
+        }
+    }
+}